@@ -22,6 +22,16 @@ mod error {
             }
         }
     }
+
+    impl serv::ServError for Error {
+        fn status(&self) -> hyper::StatusCode {
+            match self.kind() {
+                ErrorKind::Serv(e) => e.status(),
+                ErrorKind::Overflow => hyper::StatusCode::UNPROCESSABLE_ENTITY,
+                _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    }
 }
 use error::*;
 
@@ -0,0 +1,66 @@
+extern crate hyper;
+#[macro_use]
+extern crate serde_derive;
+extern crate serv;
+extern crate tokio;
+
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Request, Response, StatusCode};
+use serv::middleware::Middleware;
+use serv::RequestContext;
+use tokio::runtime::current_thread::Runtime;
+
+/// logs every request with its status
+struct Log;
+impl Middleware for Log {
+    fn response(&self, ctx: &RequestContext, resp: &mut Response<Body>) {
+        println!("{} {} -> {}", ctx.method(), ctx.uri(), resp.status());
+    }
+}
+
+/// rejects requests without `Authorization: secret`
+struct Auth;
+impl Middleware for Auth {
+    fn request(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        if req.headers().get(AUTHORIZATION) == Some(&HeaderValue::from_static("secret")) {
+            return None;
+        }
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::UNAUTHORIZED;
+        Some(resp)
+    }
+}
+
+#[derive(Serialize)]
+struct HelloResp {
+    msg: &'static str,
+}
+fn hello(_req: serv::Empty) -> serv::error::Result<HelloResp> {
+    Ok(HelloResp { msg: "hello" })
+}
+fn secret(_req: serv::Empty) -> serv::error::Result<HelloResp> {
+    Ok(HelloResp { msg: "secret" })
+}
+
+fn main() {
+    use serv::server::{Routes, Server};
+    let addr = "http://0.0.0.0:3000"
+        .parse()
+        .expect("failed to parse address");
+
+    let mut admin = Routes::new();
+    admin.push(
+        hyper::Method::GET,
+        "/admin/secret",
+        serv::sync::serv(secret),
+    );
+    admin.layer(Auth);
+
+    let mut routes = Routes::new();
+    routes.push(hyper::Method::GET, "/hello", serv::sync::serv(hello));
+    routes.merge(admin);
+    let server = Server::new(routes).with_middleware(Log);
+
+    let mut rt = Runtime::new().expect("failed to create runtime");
+    rt.block_on(server.run(addr)).expect("error on runtime");
+}
@@ -0,0 +1,44 @@
+extern crate hyper;
+#[macro_use]
+extern crate serde_derive;
+extern crate serv;
+extern crate tokio;
+
+use tokio::runtime::current_thread::Runtime;
+
+#[derive(Deserialize)]
+struct PostReq {
+    id: u64,
+    post_id: u64,
+    #[serde(default)]
+    verbose: bool,
+}
+#[derive(Serialize)]
+struct PostResp {
+    msg: String,
+}
+fn post(req: PostReq) -> serv::error::Result<PostResp> {
+    let msg = match req.verbose {
+        true => format!("user {}, post {}", req.id, req.post_id),
+        false => format!("{}/{}", req.id, req.post_id),
+    };
+    Ok(PostResp { msg })
+}
+
+fn main() {
+    use serv::server::{Routes, Server};
+    let addr = "http://0.0.0.0:3000"
+        .parse()
+        .expect("failed to parse address");
+
+    let mut routes = Routes::new();
+    routes.push(
+        hyper::Method::GET,
+        "/users/:id/posts/:post_id",
+        serv::sync::serv(post),
+    );
+    let server = Server::new(routes);
+
+    let mut rt = Runtime::new().expect("failed to create runtime");
+    rt.block_on(server.run(addr)).expect("error on runtime");
+}
@@ -0,0 +1,40 @@
+extern crate futures;
+extern crate hyper;
+extern crate serv;
+extern crate tokio;
+
+use futures::Stream;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Interval;
+
+use serv::sse::Event;
+
+/// counts up every second, resuming from `Last-Event-ID` on reconnect
+fn ticks(
+    last_event_id: Option<String>,
+    _req: serv::Empty,
+) -> Box<Stream<Item = Event<u64>, Error = serv::Error>> {
+    let start = last_event_id
+        .and_then(|id| id.parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+    let ticks = Interval::new(Instant::now(), Duration::from_secs(1))
+        .zip(futures::stream::iter_ok(start..))
+        .map(|(_instant, n)| Event::new(n).id(&n.to_string()))
+        .map_err(|e| serv::Error::from(e.to_string()));
+    Box::new(ticks)
+}
+
+fn main() {
+    use serv::server::{Routes, Server};
+    let addr = "http://0.0.0.0:3000"
+        .parse()
+        .expect("failed to parse address");
+
+    let mut routes = Routes::new();
+    routes.push(hyper::Method::GET, "/ticks", serv::sse::serv(ticks));
+    let server = Server::new(routes);
+
+    let mut rt = Runtime::new().expect("failed to create runtime");
+    rt.block_on(server.run(addr)).expect("error on runtime");
+}
@@ -1,7 +1,8 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 use super::*;
-use reply::Reply;
+use reply::{Reply, ResponseParts};
 
 /// `serv_state` build `HyperService` with given function `F` and state `S`.
 pub fn serv_state<F, S, Req, Resp, E>(state: S, f: F) -> HyperService
@@ -9,8 +10,8 @@ where
     F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
     S: 'static,
     Req: for<'de> serde::Deserialize<'de> + 'static,
-    Resp: serde::Serialize + 'static,
-    E: From<Error> + Debug + std::error::Error + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
 {
     reply::ServiceReply::serv_state(state, f)
 }
@@ -20,49 +21,136 @@ pub fn serv<F, Req, Resp, E>(f: F) -> HyperService
 where
     F: Fn(Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
     Req: for<'de> serde::Deserialize<'de> + 'static,
-    Resp: serde::Serialize + 'static,
-    E: From<Error> + Debug + std::error::Error + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
 {
     reply::ServiceReply::serv(f)
 }
 
+/// `serv_state_send` builds `HyperServiceSync` with given function `F` and state `S`.
+pub fn serv_state_send<F, S, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_state_send(state, f)
+}
+
+/// `serv_send` builds `HyperServiceSync` with given function `F`.
+pub fn serv_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: Fn(Req) -> Box<Future<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_send(f)
+}
+
+/// `serv_ctx` builds `HyperService` with given function `F`, which also receives
+/// `RequestContext` of the request. State is captured by `F`.
+pub fn serv_ctx<F, Req, Resp, E>(f: F) -> HyperService
+where
+    F: for<'a> Fn(&'a RequestContext, Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
+{
+    reply::ServiceReply::serv_ctx(f)
+}
+
+/// `serv_ctx_send` builds `HyperServiceSync` with given function `F`, which also receives
+/// `RequestContext` of the request.
+pub fn serv_ctx_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a RequestContext, Req) -> Box<Future<Item = Resp, Error = E> + Send>
+        + Send
+        + Sync
+        + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_ctx_send(f)
+}
+
+/// `serv_state_auth` builds `HyperService` with given function `F` and state `S`. `F` also
+/// receives the principal authenticated by `Auth`, requests without one get `401
+/// Unauthorized`.
+pub fn serv_state_auth<F, S, P, Req, Resp, E>(state: S, f: F) -> HyperService
+where
+    F: for<'a, 'b> Fn(&'a S, &'b P, Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
+    S: 'static,
+    P: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
+{
+    reply::ServiceReply::serv_ctx(move |ctx: &RequestContext, req| match ctx.principal() {
+        Some(principal) => f(&state, principal, req),
+        None => Box::new(err(Error::from(ErrorKind::Unauthorized).into())),
+    })
+}
+
+/// `serv_state_auth_send` builds `HyperServiceSync` with given function `F` and state `S`.
+/// `F` also receives the principal authenticated by `Auth`.
+pub fn serv_state_auth_send<F, S, P, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a, 'b> Fn(&'a S, &'b P, Req) -> Box<Future<Item = Resp, Error = E> + Send>
+        + Send
+        + Sync
+        + 'static,
+    S: Send + Sync + 'static,
+    P: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_ctx_send(move |ctx: &RequestContext, req| match ctx.principal() {
+        Some(principal) => f(&state, principal, req),
+        None => Box::new(err(Error::from(ErrorKind::Unauthorized).into())),
+    })
+}
+
 /// `AsyncServiceFn` implements `AsyncService` for given `F`
-pub(crate) struct AsyncServiceFn<F, Req, Resp, E>
+pub(crate) struct AsyncServiceFn<F, Req, Fut>
 where
-    F: Fn(Req) -> Box<Future<Item = Resp, Error = E>>,
+    F: for<'a> Fn(&'a RequestContext, Req) -> Fut,
+    Fut: Future,
     Req: 'static,
-    Resp: 'static,
 {
     f: F,
-    _req: PhantomData<Req>,
-    _resp: PhantomData<Resp>,
+    _req: PhantomData<fn(Req) -> Fut>,
 }
-impl<F, Req, Resp, E> AsyncServiceFn<F, Req, Resp, E>
+impl<F, Req, Fut> AsyncServiceFn<F, Req, Fut>
 where
-    F: Fn(Req) -> Box<Future<Item = Resp, Error = E>>,
+    F: for<'a> Fn(&'a RequestContext, Req) -> Fut,
+    Fut: Future,
     Req: 'static,
-    Resp: 'static,
 {
     pub(crate) fn new(f: F) -> Self {
         Self {
             f,
             _req: Default::default(),
-            _resp: Default::default(),
         }
     }
 }
-impl<F, Req, Resp, E> AsyncService for AsyncServiceFn<F, Req, Resp, E>
+impl<F, Req, Fut> AsyncService for AsyncServiceFn<F, Req, Fut>
 where
-    F: Fn(Req) -> Box<Future<Item = Resp, Error = E>>,
+    F: for<'a> Fn(&'a RequestContext, Req) -> Fut,
+    Fut: Future,
     Req: 'static,
-    Resp: 'static,
 {
     type Req = Req;
-    type Resp = Resp;
-    type E = E;
-    fn call(&self, req: Self::Req) -> Box<Future<Item = Self::Resp, Error = Self::E>> {
+    type Resp = Fut::Item;
+    type E = Fut::Error;
+    type Future = Fut;
+    fn call(&self, ctx: &RequestContext, req: Self::Req) -> Self::Future {
         let f = &self.f;
-        f(req)
+        f(ctx, req)
     }
 }
 
@@ -71,8 +159,21 @@ pub(crate) trait AsyncService {
     type Req;
     type Resp;
     type E;
+    type Future: Future<Item = Self::Resp, Error = Self::E>;
 
-    fn call(&self, req: Self::Req) -> Box<Future<Item = Self::Resp, Error = Self::E>>;
+    fn call(&self, ctx: &RequestContext, req: Self::Req) -> Self::Future;
+}
+
+fn with_request_id<R, T, E>(reply: R, request_id: Option<String>) -> R
+where
+    R: reply::Reply<T, E>,
+    T: serde::Serialize + 'static,
+    E: From<Error> + ServError + 'static,
+{
+    match request_id {
+        Some(id) => reply.with_request_id(&id),
+        None => reply,
+    }
 }
 
 /// `AsyncServiceStateW` implementes `tokio_service::Service` for `AsyncService`
@@ -93,9 +194,9 @@ impl<T, Req, Resp, E, Reply> hyper::service::Service for AsyncServiceStateW<T, R
 where
     T: AsyncService<Req = Req, Resp = Resp, E = E> + 'static,
     Req: for<'de> serde::Deserialize<'de> + 'static,
-    Resp: serde::Serialize + 'static,
-    E: From<Error> + 'static,
-    Reply: reply::Reply<Resp, E> + 'static,
+    Resp: IntoResponse + 'static,
+    E: From<Error> + ServError + 'static,
+    Reply: reply::Reply<Resp::Body, E> + 'static,
 {
     type ReqBody = Body;
     type ResBody = Body;
@@ -104,18 +205,73 @@ where
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let obj = self.inner.clone();
+        let codec = Codec::reply(req.headers());
+        let request_id = reply::reply_request_id(&req);
         let f = parse_req(req)
             .map_err(E::from)
-            .and_then(move |req| T::call(&obj, req))
-            .then(|resp| {
-                let status = match resp.is_ok() {
-                    true => hyper::StatusCode::OK,
-                    false => match resp.as_ref().err() {
-                        // TODO: dispatch error type?
-                        _ => hyper::StatusCode::BAD_REQUEST,
-                    },
-                };
-                Reply::from(resp).reply(status)
+            .and_then(move |(ctx, req)| T::call(&obj, &ctx, req))
+            .then(move |resp| {
+                let (parts, resp) = ResponseParts::split(resp);
+                with_request_id(Reply::from(resp), request_id)
+                    .reply_as(parts.status(), codec)
+                    .map(move |mut resp| {
+                        parts.apply(&mut resp);
+                        resp
+                    })
+            });
+        Box::new(f)
+    }
+}
+
+/// `AsyncServiceSendW` implementes `tokio_service::Service` for `Send` and `Sync` `AsyncService`
+pub(crate) struct AsyncServiceSendW<T, Reply> {
+    inner: Arc<T>,
+    reply: PhantomData<fn() -> Reply>,
+}
+impl<T, Reply> AsyncServiceSendW<T, Reply> {
+    pub(crate) fn new(t: T) -> Self {
+        Self {
+            inner: Arc::new(t),
+            reply: Default::default(),
+        }
+    }
+}
+impl<T, Reply> Clone for AsyncServiceSendW<T, Reply> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            reply: Default::default(),
+        }
+    }
+}
+
+impl<T, Req, Resp, E, Reply> hyper::service::Service for AsyncServiceSendW<T, Reply>
+where
+    T: AsyncService<Req = Req, Resp = Resp, E = E> + Send + Sync + 'static,
+    T::Future: Send,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: From<Error> + ServError + Send + 'static,
+    Reply: reply::Reply<Resp::Body, E> + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let obj = self.inner.clone();
+        let codec = Codec::reply(req.headers());
+        let request_id = reply::reply_request_id(&req);
+        let f = parse_req_send(req)
+            .map_err(E::from)
+            .and_then(move |(ctx, req)| T::call(&obj, &ctx, req))
+            .then(move |resp| {
+                let (parts, resp) = ResponseParts::split(resp);
+                let reply: Reply = with_request_id(Reply::from(resp), request_id);
+                let mut resp = reply.response_as(parts.status(), codec);
+                parts.apply(&mut resp);
+                Ok(resp)
             });
         Box::new(f)
     }
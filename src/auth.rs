@@ -0,0 +1,367 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use base64;
+use futures::future::*;
+use hyper;
+use hyper::header::*;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+
+use error::*;
+use reply::ErrorReply;
+use HyperFuture;
+use HyperFutureSend;
+use HyperService;
+use HyperServiceSync;
+use SyncObj;
+
+/// Credentials of a request, from `Authorization: Bearer`, `Authorization: Basic` or the API
+/// key header.
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    Bearer(String),
+    Basic { user: String, password: String },
+    ApiKey(String),
+}
+
+/// Future of `Auth` verifiers, resolves to the principal or `None` if credentials are invalid.
+/// Errors are replied with `500 Internal Server Error`, or `401 Unauthorized` for
+/// `ErrorKind::Unauthorized`.
+pub type VerifyFuture<P> = Box<Future<Item = Option<P>, Error = Error> + Send>;
+
+/// Authenticated principal, stored in request extensions by `Auth`.
+#[derive(Clone, Debug)]
+pub(crate) struct Principal<P>(pub(crate) P);
+
+/// `Auth` authenticates requests of routes with a verifier of `Credentials`, see `layer`.
+/// Requests without valid credentials get `401 Unauthorized` with `unauthorized` reason. The
+/// principal `P` is passed to handlers built with `serv_state_auth`, or read with
+/// `RequestContext::principal` or `RequestContext::authenticated`.
+pub struct Auth<P> {
+    verify: Arc<Fn(Credentials) -> VerifyFuture<P> + Send + Sync>,
+    api_key: Option<HeaderName>,
+    scheme: String,
+    realm: String,
+}
+
+impl<P> Clone for Auth<P> {
+    fn clone(&self) -> Self {
+        Self {
+            verify: self.verify.clone(),
+            api_key: self.api_key.clone(),
+            scheme: self.scheme.clone(),
+            realm: self.realm.clone(),
+        }
+    }
+}
+
+impl<P> Auth<P>
+where
+    P: Clone + Send + Sync + 'static,
+{
+    /// Authenticate with `verify`, which receives credentials from `Authorization` header.
+    pub fn new<F>(verify: F) -> Self
+    where
+        F: Fn(Credentials) -> VerifyFuture<P> + Send + Sync + 'static,
+    {
+        Self {
+            verify: Arc::new(verify),
+            api_key: None,
+            scheme: "Bearer".to_owned(),
+            realm: "serv".to_owned(),
+        }
+    }
+
+    /// Also accept an API key in header `name`, e.g. `X-Api-Key`.
+    pub fn api_key(mut self, name: HeaderName) -> Self {
+        self.api_key = Some(name);
+        self
+    }
+
+    /// Scheme of the challenge replied in `WWW-Authenticate` header, `Bearer` by default, e.g.
+    /// `Basic` to let browsers ask for a password.
+    pub fn scheme(mut self, scheme: &str) -> Self {
+        self.scheme = scheme.to_owned();
+        self
+    }
+
+    /// Realm replied in `WWW-Authenticate` header.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    /// Authenticate requests of `service`.
+    pub fn layer(&self, service: HyperService) -> HyperService {
+        Box::new(Authenticated {
+            auth: self.clone(),
+            inner: SyncObj::new(RefCell::new(service)),
+        })
+    }
+
+    /// Authenticate requests of `service`.
+    pub fn layer_send(&self, service: HyperServiceSync) -> HyperServiceSync {
+        Box::new(Authenticated {
+            auth: self.clone(),
+            inner: service,
+        })
+    }
+
+    /// credentials of `req`, `Authorization` header with a recognized scheme or the API key
+    fn credentials(&self, req: &Request<Body>) -> Option<Credentials> {
+        let authorization = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_authorization);
+        if authorization.is_some() {
+            return authorization;
+        }
+        let name = self.api_key.as_ref()?;
+        let key = req.headers().get(name)?.to_str().ok()?;
+        Some(Credentials::ApiKey(key.to_owned()))
+    }
+
+    /// verify credentials of `req`, resolves to `req` with the principal in extensions, or the
+    /// error response
+    fn verify(
+        &self,
+        mut req: Request<Body>,
+    ) -> Box<Future<Item = Verified, Error = hyper::Error> + Send> {
+        let to = ErrorReply::new(&req);
+        let credentials = match self.credentials(&req) {
+            Some(credentials) => credentials,
+            None => return Box::new(ok(Verified::Rejected(self.unauthorized(to)))),
+        };
+        let auth = self.clone();
+        let f = (self.verify)(credentials).then(move |res| {
+            let verified = match res {
+                Ok(Some(principal)) => {
+                    req.extensions_mut().insert(Principal(principal));
+                    Verified::Request(req)
+                }
+                Ok(None) => Verified::Rejected(auth.unauthorized(to)),
+                Err(Error(ErrorKind::Unauthorized, _)) => Verified::Rejected(auth.unauthorized(to)),
+                Err(e) => {
+                    warn!("failed to verify credentials: {}", e);
+                    let status = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+                    Verified::Rejected(to.status_response(e, status))
+                }
+            };
+            Ok(verified)
+        });
+        Box::new(f)
+    }
+
+    fn unauthorized(&self, to: ErrorReply) -> Response<Body> {
+        let mut resp = to.response(ErrorKind::Unauthorized);
+        let challenge = format!("{} realm=\"{}\"", self.scheme, self.realm);
+        if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+            resp.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+        resp
+    }
+}
+
+fn parse_authorization(value: &str) -> Option<Credentials> {
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next()?;
+    let param = parts.next()?.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Bearer(param.to_owned()))
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = base64::decode(param).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let user = parts.next()?.to_owned();
+        let password = parts.next()?.to_owned();
+        Some(Credentials::Basic { user, password })
+    } else {
+        None
+    }
+}
+
+/// Outcome of `Auth::verify`.
+enum Verified {
+    /// authenticated request
+    Request(Request<Body>),
+    /// error response
+    Rejected(Response<Body>),
+}
+
+/// Route service `S` behind `Auth`. The verifier future calls the service once the request is
+/// authenticated, with a clone of it for `Send` services.
+#[derive(Clone)]
+struct Authenticated<P, S> {
+    auth: Auth<P>,
+    inner: S,
+}
+
+impl<P> Service for Authenticated<P, SyncObj<RefCell<HyperService>>>
+where
+    P: Clone + Send + Sync + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFuture;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let inner = self.inner.clone();
+        let f = self
+            .auth
+            .verify(req)
+            .and_then(move |verified| -> HyperFuture {
+                match verified {
+                    Verified::Request(req) => inner.borrow_mut().call(req),
+                    Verified::Rejected(resp) => Box::new(ok(resp)),
+                }
+            });
+        Box::new(f)
+    }
+}
+
+impl<P> Service for Authenticated<P, HyperServiceSync>
+where
+    P: Clone + Send + Sync + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let f = self
+            .auth
+            .verify(req)
+            .and_then(move |verified| -> HyperFutureSend {
+                match verified {
+                    Verified::Request(req) => inner.call(req),
+                    Verified::Rejected(resp) => Box::new(ok(resp)),
+                }
+            });
+        Box::new(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+    use serde_json;
+
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use {Empty, RequestContext};
+
+    fn client() -> TestClient {
+        let auth = Auth::new(|credentials| -> VerifyFuture<String> {
+            let res = match credentials {
+                Credentials::Bearer(ref token) if token == "token" => Ok(Some("alice".to_owned())),
+                Credentials::Bearer(ref token) if token == "expired" => {
+                    Err(ErrorKind::Unauthorized.into())
+                }
+                Credentials::Bearer(ref token) if token == "unavailable" => {
+                    Err("store is down".into())
+                }
+                Credentials::Basic { user, password } if password == "secret" => Ok(Some(user)),
+                Credentials::ApiKey(ref key) if key == "key" => Ok(Some("bob".to_owned())),
+                _ => Ok(None),
+            };
+            Box::new(result(res))
+        })
+        .api_key(HeaderName::from_static("x-api-key"))
+        .scheme("Basic")
+        .realm("test");
+        let whoami = || {
+            sync::serv_ctx(|ctx: &RequestContext, _req: Empty| -> Result<String> {
+                ctx.authenticated::<String>().map(|user| user.to_owned())
+            })
+        };
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/whoami", auth.layer(whoami()));
+        routes.push(Method::GET, "/anonymous", whoami());
+        let greet = sync::serv_state_auth(
+            "hello".to_owned(),
+            |greeting: &String, user: &String, _req: Empty| -> Result<String> {
+                Ok(format!("{} {}", greeting, user))
+            },
+        );
+        routes.push(Method::GET, "/greet", auth.layer(greet));
+        TestClient::new(Server::new(routes).with_request_id_in_errors())
+    }
+
+    fn whoami(client: &mut TestClient, authorization: Option<&str>) -> TestResponse<String> {
+        let mut req = Request::get("/whoami");
+        if let Some(authorization) = authorization {
+            req.header(AUTHORIZATION, authorization);
+        }
+        client.request(req.body(Body::empty()).unwrap())
+    }
+
+    #[test]
+    fn principal_is_passed_to_handler() {
+        let mut client = client();
+        let resp = whoami(&mut client, Some("Bearer token"));
+        assert_eq!(resp.into_result(), "alice");
+        // alice:secret
+        let resp = whoami(&mut client, Some("Basic YWxpY2U6c2VjcmV0"));
+        assert_eq!(resp.into_result(), "alice");
+    }
+
+    #[test]
+    fn invalid_credentials_are_challenged() {
+        let mut client = client();
+        for authorization in &[None, Some("Bearer nope"), Some("Bearer expired")] {
+            let resp = whoami(&mut client, *authorization);
+            resp.assert_status(StatusCode::UNAUTHORIZED)
+                .assert_reason("unauthorized");
+            let challenge = resp.headers().get(WWW_AUTHENTICATE).unwrap();
+            assert_eq!(challenge, "Basic realm=\"test\"");
+        }
+    }
+
+    #[test]
+    fn verifier_error_is_internal() {
+        let mut client = client();
+        let resp = whoami(&mut client, Some("Bearer unavailable"));
+        resp.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(body["request_id"].is_string(), "no request_id: {}", body);
+    }
+
+    #[test]
+    fn routes_without_auth_are_unauthenticated() {
+        let req = Request::get("/anonymous")
+            .header(AUTHORIZATION, "Bearer token")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<String> = client().request(req);
+        resp.assert_status(StatusCode::UNAUTHORIZED)
+            .assert_reason("unauthorized");
+    }
+
+    #[test]
+    fn principal_is_passed_to_typed_handler() {
+        let req = Request::get("/greet")
+            .header(AUTHORIZATION, "Bearer token")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<String> = client().request(req);
+        assert_eq!(resp.into_result(), "hello alice");
+    }
+
+    #[test]
+    fn api_key_is_used_for_other_schemes() {
+        let req = Request::get("/whoami")
+            .header(AUTHORIZATION, "Digest username=\"alice\"")
+            .header("x-api-key", "key")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<String> = client().request(req);
+        assert_eq!(resp.into_result(), "bob");
+    }
+}
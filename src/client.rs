@@ -0,0 +1,316 @@
+use std;
+use std::fmt;
+
+use futures::future::*;
+use futures::Stream;
+use hyper;
+use hyper::client::HttpConnector;
+use hyper::header::*;
+use hyper::{Body, Request, StatusCode};
+use serde;
+use serde_json;
+use serde_qs;
+use url;
+
+use error::*;
+use reply::RemoteReply;
+
+/// `Client` calls serv APIs at `http://host:port` or `http+unix:///path/to/socket`, the same
+/// urls as `Server::run`. Requests are encoded the way `parse_req` decodes them: query string
+/// for `GET` and `DELETE`, JSON body otherwise.
+#[derive(Clone)]
+pub struct Client {
+    inner: Inner,
+    /// prefix of request uris
+    base: String,
+}
+
+#[derive(Clone)]
+enum Inner {
+    Tcp(hyper::Client<HttpConnector>),
+    #[cfg(feature = "uds")]
+    Unix(hyper::Client<uds::UnixConnector>),
+}
+
+impl Client {
+    pub fn new(url: url::Url) -> Result<Self> {
+        match url.scheme() {
+            "http" => Ok(Self {
+                inner: Inner::Tcp(hyper::Client::new()),
+                base: url.as_str().trim_end_matches('/').to_owned(),
+            }),
+            "http+unix" => Self::new_uds(url),
+            scheme => bail!("unexpected scheme: {}", scheme),
+        }
+    }
+
+    #[cfg(feature = "uds")]
+    fn new_uds(url: url::Url) -> Result<Self> {
+        let connector = uds::UnixConnector::new(url.path());
+        Ok(Self {
+            inner: Inner::Unix(hyper::Client::builder().build(connector)),
+            // authority is not used to connect
+            base: "http://localhost".to_owned(),
+        })
+    }
+
+    #[cfg(not(feature = "uds"))]
+    fn new_uds(url: url::Url) -> Result<Self> {
+        bail!("uds not supported: {}", url)
+    }
+
+    pub fn get<Req, Resp>(&self, path: &str, req: &Req) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        self.call(hyper::Method::GET, path, req)
+    }
+
+    pub fn delete<Req, Resp>(&self, path: &str, req: &Req) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        self.call(hyper::Method::DELETE, path, req)
+    }
+
+    pub fn post<Req, Resp>(&self, path: &str, req: &Req) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        self.call(hyper::Method::POST, path, req)
+    }
+
+    pub fn put<Req, Resp>(&self, path: &str, req: &Req) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        self.call(hyper::Method::PUT, path, req)
+    }
+
+    pub fn patch<Req, Resp>(&self, path: &str, req: &Req) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        self.call(hyper::Method::PATCH, path, req)
+    }
+
+    /// Call `method` at `path` with `req`, and decode `result` of the reply.
+    pub fn call<Req, Resp>(
+        &self,
+        method: hyper::Method,
+        path: &str,
+        req: &Req,
+    ) -> ClientFuture<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let uri = format!("{}{}", self.base, path);
+        let req = match encode_req(method, &uri, req) {
+            Ok(req) => req,
+            Err(e) => return Box::new(err(e.into())),
+        };
+        let resp = match self.inner {
+            Inner::Tcp(ref client) => client.request(req),
+            #[cfg(feature = "uds")]
+            Inner::Unix(ref client) => client.request(req),
+        };
+        let f = resp
+            .and_then(|resp| {
+                let status = resp.status();
+                resp.into_body().concat2().map(move |body| (status, body))
+            })
+            .map_err(|e| RemoteError::from(Error::from(e)))
+            .and_then(|(status, body)| decode_reply(status, &body));
+        Box::new(f)
+    }
+}
+
+pub type ClientFuture<T> = Box<Future<Item = T, Error = RemoteError> + Send>;
+
+/// request of `method` to `uri` with `req`, encoded the way `parse_req` decodes it
+pub(crate) fn encode_req<Req>(method: hyper::Method, uri: &str, req: &Req) -> Result<Request<Body>>
+where
+    Req: serde::Serialize,
+{
+    let req = match method {
+        hyper::Method::GET | hyper::Method::DELETE => {
+            let qs = serde_qs::to_string(req).map_err(|e| ErrorKind::EncodeBody(e.to_string()))?;
+            let uri = if qs.is_empty() {
+                uri.to_owned()
+            } else {
+                format!("{}?{}", uri, qs)
+            };
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(ACCEPT, "application/json")
+                .body(Body::empty())?
+        }
+        _ => {
+            let body = serde_json::to_vec(req).map_err(ErrorKind::EncodeJson)?;
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(ACCEPT, "application/json")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))?
+        }
+    };
+    Ok(req)
+}
+
+fn decode_reply<T>(status: StatusCode, body: &[u8]) -> std::result::Result<T, RemoteError>
+where
+    T: for<'de> serde::Deserialize<'de>,
+{
+    match serde_json::from_slice(body) {
+        Ok(RemoteReply::Ok { result }) => Ok(result),
+        Ok(RemoteReply::Err { reason, msg }) => Err(RemoteError::Reply {
+            status,
+            reason,
+            msg,
+        }),
+        Err(_e) if !status.is_success() => Err(RemoteError::Status(status)),
+        Err(e) => Err(Error::from(ErrorKind::DecodeJson(e)).into()),
+    }
+}
+
+/// Error of `Client` calls.
+#[derive(Debug)]
+pub enum RemoteError {
+    /// error reply of the server
+    Reply {
+        status: StatusCode,
+        reason: String,
+        msg: Option<String>,
+    },
+    /// error status without reply, e.g. from a proxy
+    Status(StatusCode),
+    /// failed to send request or decode reply
+    Request(Error),
+}
+
+impl RemoteError {
+    /// `reason` of the error reply
+    pub fn reason(&self) -> Option<&str> {
+        match *self {
+            RemoteError::Reply { ref reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for RemoteError {
+    fn from(e: Error) -> Self {
+        RemoteError::Request(e)
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RemoteError::Reply {
+                status, ref reason, ..
+            } => write!(f, "{} {}", status, reason),
+            RemoteError::Status(status) => write!(f, "{}", status),
+            RemoteError::Request(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {
+    /// `reason` of the error reply, so that handlers which fail with `RemoteError` reply the
+    /// same reason
+    fn description(&self) -> &str {
+        match *self {
+            RemoteError::Reply { ref reason, .. } => reason,
+            RemoteError::Status(_) => "remote",
+            RemoteError::Request(ref e) => e.description(),
+        }
+    }
+}
+
+impl ServError for RemoteError {
+    fn status(&self) -> StatusCode {
+        match *self {
+            RemoteError::Reply { status, .. } | RemoteError::Status(status) => status,
+            RemoteError::Request(ref e) => e.status(),
+        }
+    }
+}
+
+#[cfg(feature = "uds")]
+mod uds {
+    use std::io;
+    use std::path::PathBuf;
+
+    use futures::Future;
+    use hyper::client::connect::{Connect, Connected, Destination};
+    use tokio_uds::UnixStream;
+
+    /// connects to the unix domain socket at `path` for every destination
+    #[derive(Clone)]
+    pub(super) struct UnixConnector {
+        path: PathBuf,
+    }
+
+    impl UnixConnector {
+        pub(super) fn new(path: &str) -> Self {
+            Self { path: path.into() }
+        }
+    }
+
+    impl Connect for UnixConnector {
+        type Transport = UnixStream;
+        type Error = io::Error;
+        type Future = Box<Future<Item = (UnixStream, Connected), Error = io::Error> + Send>;
+
+        fn connect(&self, _dst: Destination) -> Self::Future {
+            Box::new(UnixStream::connect(&self.path).map(|stream| (stream, Connected::new())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_current_thread as current_thread;
+
+    use server::{Routes, Server};
+    use sync;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Rename {
+        name: String,
+    }
+
+    #[test]
+    fn patch_is_replied() {
+        let rename = sync::serv(|req: Rename| -> Result<String> { Ok(req.name) });
+        let mut routes = Routes::new();
+        routes.push(Method::PATCH, "/name", rename);
+        let server = Server::new(routes);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = Client::new(format!("http://{}", addr).parse().unwrap()).unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let f = lazy(move || {
+            let server = server.run_listener(listener);
+            current_thread::spawn(server.map_err(|e| panic!("server failed: {}", e)));
+            let req = Rename {
+                name: "bob".to_owned(),
+            };
+            client.patch::<_, String>("/name", &req)
+        });
+        assert_eq!(rt.block_on(f).unwrap(), "bob");
+    }
+}
@@ -0,0 +1,229 @@
+use hyper::header::*;
+use serde;
+use serde_json;
+
+use error::*;
+
+/// Encoding of request and reply bodies, selected by `Content-Type` and `Accept` headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "application/cbor",
+        }
+    }
+
+    /// codec of MIME type `mime`, parameters like `charset` are ignored
+    pub fn from_mime(mime: &str) -> Option<Codec> {
+        let mime = mime.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Codec::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" => Some(Codec::MsgPack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    /// codec of request body, JSON if `Content-Type` is missing, `unsupported_encoding` error
+    /// if it is not supported
+    pub(crate) fn request(headers: &HeaderMap) -> Result<Codec> {
+        let mime = match headers.get(CONTENT_TYPE) {
+            Some(mime) => mime,
+            None => return Ok(Codec::default()),
+        };
+        let mime = mime.to_str().unwrap_or("");
+        match Codec::from_mime(mime) {
+            Some(codec) => Ok(codec),
+            None => bail!(ErrorKind::UnsupportedEncoding(mime.to_owned())),
+        }
+    }
+
+    /// codec of reply, the first supported type in `Accept` or the codec of request body
+    pub(crate) fn reply(headers: &HeaderMap) -> Codec {
+        let accept = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for mime in accept {
+            // `q=0` means not acceptable
+            let rejected = mime.split(';').skip(1).any(|param| {
+                let mut kv = param.splitn(2, '=');
+                kv.next().map(|k| k.trim()) == Some("q")
+                    && kv.next().and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            });
+            if rejected {
+                continue;
+            }
+            if let Some(codec) = Codec::from_mime(mime) {
+                return codec;
+            }
+        }
+        Codec::request(headers).unwrap_or_default()
+    }
+
+    pub(crate) fn decode<R>(self, body: &[u8]) -> Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        match self {
+            Codec::Json => {
+                serde_json::from_slice(body).map_err(|e| ErrorKind::DecodeJson(e).into())
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => ::rmp_serde::from_slice(body)
+                .map_err(|e| ErrorKind::DecodeBody(e.to_string()).into()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ::serde_cbor::from_slice(body)
+                .map_err(|e| ErrorKind::DecodeBody(e.to_string()).into()),
+        }
+    }
+
+    pub(crate) fn encode<T>(self, value: &T) -> Result<Vec<u8>>
+    where
+        T: serde::Serialize,
+    {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|e| ErrorKind::EncodeJson(e).into()),
+            // struct fields are encoded by name, as the reply envelope is an internally tagged enum
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => ::rmp_serde::to_vec_named(value)
+                .map_err(|e| ErrorKind::EncodeBody(e.to_string()).into()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                ::serde_cbor::to_vec(value).map_err(|e| ErrorKind::EncodeBody(e.to_string()).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Method, Request, StatusCode};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Echo {
+        name: String,
+        n: u32,
+    }
+
+    fn client() -> TestClient {
+        let echo = sync::serv(|req: Echo| -> Result<Echo> { Ok(req) });
+        let mut routes = Routes::new();
+        routes.push(Method::POST, "/echo", echo);
+        TestClient::new(Server::new(routes))
+    }
+
+    fn post(path: &str, mime: &str, accept: &str, body: Vec<u8>) -> TestResponse<Value> {
+        let req = Request::post(path)
+            .header(CONTENT_TYPE, mime)
+            .header(ACCEPT, accept)
+            .body(Body::from(body))
+            .unwrap();
+        client().request(req)
+    }
+
+    fn echo() -> Echo {
+        Echo {
+            name: "bob".to_owned(),
+            n: 7,
+        }
+    }
+
+    /// reply envelope `{"status":"ok","result":...}` of `echo()`
+    fn echo_reply() -> Value {
+        json!({ "status": "ok", "result": { "name": "bob", "n": 7 } })
+    }
+
+    fn content_type<T>(resp: &TestResponse<T>) -> &str {
+        resp.headers()[CONTENT_TYPE].to_str().unwrap()
+    }
+
+    #[test]
+    fn unsupported_content_type_is_rejected() {
+        let body = serde_json::to_vec(&echo()).unwrap();
+        let resp = post("/echo", "text/plain", "application/json", body);
+        resp.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        resp.assert_reason("unsupported_encoding");
+    }
+
+    #[test]
+    fn json_parameters_are_ignored() {
+        let body = serde_json::to_vec(&echo()).unwrap();
+        let resp = post("/echo", "application/json; charset=utf-8", "*/*", body);
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            echo_reply()
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        let body = ::rmp_serde::to_vec_named(&echo()).unwrap();
+        let resp = post("/echo", "application/msgpack", "application/msgpack", body);
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(content_type(&resp), "application/msgpack");
+        let reply: Value = ::rmp_serde::from_slice(resp.body()).unwrap();
+        assert_eq!(reply, echo_reply());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        let body = ::serde_cbor::to_vec(&echo()).unwrap();
+        let resp = post("/echo", "application/cbor", "application/cbor", body);
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(content_type(&resp), "application/cbor");
+        let reply: Value = ::serde_cbor::from_slice(resp.body()).unwrap();
+        assert_eq!(reply, echo_reply());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn errors_are_encoded_as_accepted() {
+        let body = serde_json::to_vec(&echo()).unwrap();
+        let resp = post("/missing", "application/json", "application/cbor", body);
+        resp.assert_status(StatusCode::NOT_FOUND);
+        assert_eq!(content_type(&resp), "application/cbor");
+        let reply: Value = ::serde_cbor::from_slice(resp.body()).unwrap();
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["reason"], "invalid_endpoint");
+
+        let resp = post("/echo", "text/plain", "application/cbor", Vec::new());
+        resp.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(content_type(&resp), "application/cbor");
+        let reply: Value = ::serde_cbor::from_slice(resp.body()).unwrap();
+        assert_eq!(reply["reason"], "unsupported_encoding");
+    }
+
+    #[test]
+    fn errors_are_json_by_default() {
+        let resp = post("/missing", "application/json", "*/*", Vec::new());
+        resp.assert_status(StatusCode::NOT_FOUND);
+        assert_eq!(content_type(&resp), "application/json");
+        resp.assert_reason("invalid_endpoint");
+    }
+}
@@ -0,0 +1,423 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use brotli;
+use bytes::Bytes;
+use flate2;
+use futures::future::*;
+use futures::Stream;
+use hyper;
+use hyper::body::Payload;
+use hyper::header::*;
+use hyper::{Body, HeaderMap, Request, Response};
+use zstd;
+
+use error::*;
+
+/// Smallest body compressed by default, in bytes.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// Content coding of compressed replies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// encoding of content coding `name`
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    fn decoder<'a>(self, data: &'a [u8]) -> io::Result<Box<Read + 'a>> {
+        Ok(match self {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(data)?),
+            Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(data)),
+        })
+    }
+
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                w.write_all(data)?;
+                Ok(w.into_inner())
+            }
+            Encoding::Zstd => zstd::bulk::compress(data, 3),
+            Encoding::Gzip => {
+                let mut w =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(data)?;
+                w.finish()
+            }
+        }
+    }
+}
+
+/// Compression of replies, negotiated by `Accept-Encoding`, see `Server::with_compression`
+/// and `Routes::set_compression`.
+///
+/// Only bodies of known size are compressed, streamed bodies such as SSE or NDJSON are sent as
+/// is. Replies which already have `Content-Encoding`, or are media types which are compressed
+/// already (images, audio, video, archives) are not compressed either.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+    /// supported encodings, preferred first
+    encodings: Vec<Encoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress bodies of at least `min_size` bytes, `DEFAULT_MIN_SIZE` by default.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Encodings to use, preferred first, when the client accepts them with the same quality.
+    /// Brotli, zstd and gzip by default.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// encoding for request `headers` with the highest quality, server preference breaks ties
+    fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let accepted = accepted_encodings(headers);
+        let quality = |encoding: Encoding| {
+            let listed = accepted
+                .iter()
+                .find(|&&(ref name, _q)| name == encoding.as_str());
+            let wildcard = accepted.iter().find(|&&(ref name, _q)| name == "*");
+            listed.or(wildcard).map(|&(_, q)| q).unwrap_or(0)
+        };
+        let mut best = None;
+        for &encoding in &self.encodings {
+            let q = quality(encoding);
+            if q > 0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _q)| encoding)
+    }
+}
+
+/// Codings of `Accept-Encoding` with quality in thousandths, lowercased.
+fn accepted_encodings(headers: &HeaderMap) -> Vec<(String, u16)> {
+    let mut accepted = Vec::new();
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_e) => continue,
+        };
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            let mut q = 1000;
+            for param in parts {
+                let mut kv = param.splitn(2, '=');
+                if kv.next().map(|k| k.trim().eq_ignore_ascii_case("q")) == Some(true) {
+                    let v = kv.next().unwrap_or_default().trim();
+                    q = v.parse::<f32>().map(|v| (v * 1000.0) as u16).unwrap_or(0);
+                }
+            }
+            accepted.push((name, q));
+        }
+    }
+    accepted
+}
+
+/// Decode request body `data` of content coding `name`. At most `limit` bytes are decoded,
+/// so that small bodies which decode to a lot of data are rejected early.
+pub(crate) fn decode(name: &str, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let encoding = match Encoding::from_name(name) {
+        Some(encoding) => encoding,
+        None => bail!(ErrorKind::UnsupportedEncoding(name.to_owned())),
+    };
+    let mut decoded = Vec::new();
+    encoding
+        .decoder(data)
+        .and_then(|decoder| decoder.take(limit as u64 + 1).read_to_end(&mut decoded))
+        .map_err(|e| ErrorKind::DecodeBody(e.to_string()))?;
+    if decoded.len() > limit {
+        bail!(ErrorKind::BodyTooLarge(limit));
+    }
+    Ok(decoded)
+}
+
+/// Compression of the routed request, stored in request extensions by `Server`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Compress(pub(crate) bool);
+
+/// Compression negotiated for a request, see `Compressor::new`.
+pub(crate) struct Compressor {
+    compression: Arc<Compression>,
+    /// `None` if the client accepts no supported encoding
+    encoding: Option<Encoding>,
+}
+
+impl Compressor {
+    /// compressor of replies to `req`, if the route enables compression
+    pub(crate) fn new(compression: &Arc<Compression>, req: &Request<Body>) -> Option<Self> {
+        match req.extensions().get::<Compress>() {
+            Some(&Compress(true)) => {}
+            _ => return None,
+        }
+        let encoding = if req.method() == hyper::Method::HEAD {
+            None
+        } else {
+            compression.negotiate(req.headers())
+        };
+        Some(Self {
+            compression: compression.clone(),
+            encoding,
+        })
+    }
+
+    /// compress response of `f`
+    pub(crate) fn compress<F>(
+        self,
+        f: F,
+    ) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+    where
+        F: Future<Item = Response<Body>, Error = hyper::Error>,
+    {
+        let Compressor {
+            compression,
+            encoding,
+        } = self;
+        f.and_then(move |mut resp| {
+            let len = match body_len(&resp) {
+                Some(len) => len,
+                None => return Either::A(ok(resp)),
+            };
+            // the reply depends on Accept-Encoding even if it is not compressed for this request
+            resp.headers_mut()
+                .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+            let encoding = match encoding {
+                Some(encoding) if len >= compression.min_size => encoding,
+                _ => return Either::A(ok(resp)),
+            };
+            let (mut parts, body) = resp.into_parts();
+            Either::B(body.concat2().map(move |body| {
+                let encoded = match encoding.encode(&body) {
+                    // compressed larger than the body, e.g. random data
+                    Ok(ref encoded) if encoded.len() >= body.len() => None,
+                    Ok(encoded) => Some(encoded),
+                    Err(e) => {
+                        warn!("failed to compress reply: {}", e);
+                        None
+                    }
+                };
+                let body: Bytes = match encoded {
+                    Some(encoded) => {
+                        parts.headers.insert(
+                            CONTENT_ENCODING,
+                            HeaderValue::from_static(encoding.as_str()),
+                        );
+                        encoded.into()
+                    }
+                    None => body.into_bytes(),
+                };
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                Response::from_parts(parts, Body::from(body))
+            }))
+        })
+    }
+}
+
+/// size of the body of `resp` if it can be compressed: the body is complete, not encoded yet,
+/// and not of a compressed media type
+fn body_len(resp: &Response<Body>) -> Option<usize> {
+    let status = resp.status();
+    if status.is_informational()
+        || status == hyper::StatusCode::NO_CONTENT
+        || status == hyper::StatusCode::NOT_MODIFIED
+        || resp.headers().contains_key(CONTENT_ENCODING)
+    {
+        return None;
+    }
+    if let Some(content_type) = resp.headers().get(CONTENT_TYPE) {
+        let content_type = content_type
+            .to_str()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if compressed_media(&content_type) {
+            return None;
+        }
+    }
+    resp.body().content_length().map(|len| len as usize)
+}
+
+fn compressed_media(content_type: &str) -> bool {
+    let archives = [
+        "application/gzip",
+        "application/x-gzip",
+        "application/zip",
+        "application/zstd",
+    ];
+    (content_type.starts_with("image/") && !content_type.starts_with("image/svg"))
+        || content_type.starts_with("audio/")
+        || content_type.starts_with("video/")
+        || archives
+            .iter()
+            .any(|archive| content_type.starts_with(archive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+    use serde_json;
+
+    use reply::RemoteReply;
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use Empty;
+
+    fn client() -> TestClient {
+        let text =
+            |len: usize| sync::serv(move |_req: Empty| -> Result<String> { Ok("a".repeat(len)) });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/large", text(2000));
+        routes.push(Method::GET, "/small", text(10));
+        routes.push(Method::GET, "/plain", text(2000));
+        routes.set_compression(Method::GET, "/plain", false);
+        TestClient::new(Server::new(routes).with_compression(Compression::new()))
+    }
+
+    fn get(client: &mut TestClient, path: &str, accept: &str) -> TestResponse<String> {
+        let req = Request::get(path)
+            .header(ACCEPT_ENCODING, accept)
+            .body(Body::empty())
+            .unwrap();
+        client.request(req)
+    }
+
+    /// `result` of `resp` decoded by its `Content-Encoding`
+    fn decoded(resp: &TestResponse<String>) -> String {
+        let body = match resp.headers().get(CONTENT_ENCODING) {
+            Some(coding) => decode(coding.to_str().unwrap(), resp.body(), 1 << 20).unwrap(),
+            None => resp.body().to_vec(),
+        };
+        match serde_json::from_slice(&body).unwrap() {
+            RemoteReply::Ok { result } => result,
+            RemoteReply::Err { reason, msg } => panic!("error reply {}: {:?}", reason, msg),
+        }
+    }
+
+    #[test]
+    fn replies_are_compressed_by_accepted_encoding() {
+        let mut client = client();
+        for &(accept, coding) in &[
+            ("gzip", "gzip"),
+            ("gzip, zstd", "zstd"),
+            ("br;q=0.5, gzip", "gzip"),
+            ("*", "br"),
+        ] {
+            let resp = get(&mut client, "/large", accept);
+            resp.assert_status(StatusCode::OK);
+            assert_eq!(resp.headers()[CONTENT_ENCODING], coding, "{}", accept);
+            assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+            assert!(resp.body().len() < 2000);
+            assert_eq!(decoded(&resp), "a".repeat(2000));
+        }
+    }
+
+    #[test]
+    fn replies_are_not_compressed() {
+        let mut client = client();
+        for &(path, accept) in &[
+            ("/large", "identity"),
+            ("/large", "gzip;q=0"),
+            ("/small", "gzip"),
+            ("/plain", "gzip"),
+        ] {
+            let resp = get(&mut client, path, accept);
+            assert!(
+                !resp.headers().contains_key(CONTENT_ENCODING),
+                "{} {}",
+                path,
+                accept
+            );
+            assert!(decoded(&resp).starts_with('a'));
+        }
+    }
+
+    fn post(client: &mut TestClient, coding: &str, body: Vec<u8>) -> TestResponse<String> {
+        let req = Request::post("/echo")
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, coding)
+            .body(Body::from(body))
+            .unwrap();
+        client.request(req)
+    }
+
+    fn echo() -> TestClient {
+        let echo = sync::serv(|req: String| -> Result<String> { Ok(req) });
+        let mut routes = Routes::new();
+        routes.push(Method::POST, "/echo", echo);
+        routes.set_body_limit(Method::POST, "/echo", 1024);
+        TestClient::new(Server::new(routes))
+    }
+
+    #[test]
+    fn request_bodies_are_decoded() {
+        let mut client = echo();
+        for &encoding in &[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+            let body = encoding.encode(b"\"hello\"").unwrap();
+            let resp = post(&mut client, encoding.as_str(), body);
+            assert_eq!(resp.into_result(), "hello");
+        }
+    }
+
+    #[test]
+    fn request_bodies_are_rejected() {
+        let mut client = echo();
+        post(&mut client, "compress", b"\"hello\"".to_vec())
+            .assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .assert_reason("unsupported_encoding");
+        // small when compressed, over the limit when decoded
+        let large = format!("\"{}\"", "a".repeat(4096));
+        let body = Encoding::Gzip.encode(large.as_bytes()).unwrap();
+        assert!(body.len() < 1024);
+        post(&mut client, "gzip", body)
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE)
+            .assert_reason("body_too_large");
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use tokio::timer::Delay;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Connection accepted by `Server`. Reads fail with `TimedOut` if the head of the first
+/// request is not received in time, see `ServerConfig::header_read_timeout`.
+pub(crate) struct Conn<IO> {
+    io: IO,
+    head_timeout: Option<HeadTimeout>,
+}
+
+struct HeadTimeout {
+    delay: Delay,
+    /// set by the service of the connection when it is called
+    received: Arc<AtomicBool>,
+}
+
+impl<IO> Conn<IO> {
+    pub(crate) fn new(io: IO, header_read_timeout: Option<Duration>) -> Self {
+        let head_timeout = header_read_timeout.map(|timeout| HeadTimeout {
+            delay: Delay::new(Instant::now() + timeout),
+            received: Arc::new(AtomicBool::new(false)),
+        });
+        Self { io, head_timeout }
+    }
+
+    pub(crate) fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    /// flag to set once the head of a request is received
+    pub(crate) fn head_received(&self) -> Option<Arc<AtomicBool>> {
+        self.head_timeout
+            .as_ref()
+            .map(|timeout| timeout.received.clone())
+    }
+
+    fn poll_head_timeout(&mut self) -> io::Result<()> {
+        let expired = match self.head_timeout {
+            Some(ref mut timeout) if !timeout.received.load(Ordering::Relaxed) => {
+                match timeout.delay.poll() {
+                    Ok(Async::Ready(())) => true,
+                    Ok(Async::NotReady) => return Ok(()),
+                    // e.g. no timer on the executor, the connection is served without timeout
+                    Err(e) => {
+                        warn!("failed to start header read timer: {}", e);
+                        false
+                    }
+                }
+            }
+            _ => false,
+        };
+        // the timer is not needed once the head is received
+        self.head_timeout = None;
+        if expired {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "header read timeout",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<IO> Read for Conn<IO>
+where
+    IO: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll_head_timeout()?;
+        self.io.read(buf)
+    }
+}
+
+impl<IO> Write for Conn<IO>
+where
+    IO: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<IO> AsyncRead for Conn<IO> where IO: AsyncRead {}
+
+impl<IO> AsyncWrite for Conn<IO>
+where
+    IO: AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_read_timer_error_keeps_connection() {
+        // without a runtime there is no timer, and the delay fails
+        let mut conn = Conn::new(&b"GET / HTTP/1.1\r\n"[..], Some(Duration::from_secs(60)));
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"GET / HTTP/1.1\r\n");
+    }
+}
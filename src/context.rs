@@ -0,0 +1,288 @@
+use std;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http::Extensions;
+use hyper::{HeaderMap, Method, Request, Uri};
+
+use auth::Principal;
+use error::*;
+use path::PathParams;
+#[cfg(feature = "tls")]
+use tls::ClientCert;
+
+/// Address of the connected client.
+#[derive(Clone, Debug)]
+pub enum PeerAddr {
+    Tcp(std::net::SocketAddr),
+    #[cfg(feature = "uds")]
+    Unix(std::os::unix::net::SocketAddr),
+}
+
+/// Route pattern which matched the request, e.g. `/users/:id`.
+#[derive(Clone, Debug)]
+pub(crate) struct MatchedRoute(pub(crate) String);
+
+/// Time by which the route should reply, see `Server::with_timeout`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(pub(crate) Instant);
+
+/// Header of the request id, taken from the request if present and echoed in the response.
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Id of the request, see `Server::call`. `reply` is set if the id should be included in error
+/// replies.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId {
+    pub(crate) id: String,
+    pub(crate) reply: bool,
+}
+
+static SEED: AtomicUsize = AtomicUsize::new(0);
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl RequestId {
+    /// id from `X-Request-Id` header of `req`, or a new one if it is missing or malformed
+    pub(crate) fn new<B>(req: &Request<B>, reply: bool) -> Self {
+        let id = req
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| valid_id(id))
+            .map(|id| id.to_owned())
+            .unwrap_or_else(generate_id);
+        Self { id, reply }
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// id unique to the process, prefixed with a seed to tell processes apart
+fn generate_id() -> String {
+    let mut seed = SEED.load(Ordering::Relaxed);
+    if seed == 0 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as usize)
+            .unwrap_or(0);
+        let new_seed = (now ^ (std::process::id() as usize).rotate_left(20)) | 1;
+        seed = match SEED.compare_exchange(0, new_seed, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => new_seed,
+            Err(seed) => seed,
+        };
+    }
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}", seed, n)
+}
+
+/// Request metadata for handlers, everything except the body of `hyper::Request`.
+#[derive(Debug)]
+pub struct RequestContext {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    extensions: Extensions,
+}
+
+impl RequestContext {
+    /// split `req` into context and body
+    pub(crate) fn from_request<B>(req: Request<B>) -> (Self, B) {
+        let (parts, body) = req.into_parts();
+        let ctx = Self {
+            method: parts.method,
+            uri: parts.uri,
+            headers: parts.headers,
+            extensions: parts.extensions,
+        };
+        (ctx, body)
+    }
+
+    /// copy of `req` without body, only extensions known to `serv` are copied
+    pub(crate) fn head<B>(req: &Request<B>) -> Self {
+        let mut extensions = Extensions::new();
+        if let Some(peer) = req.extensions().get::<PeerAddr>() {
+            extensions.insert(peer.clone());
+        }
+        if let Some(route) = req.extensions().get::<MatchedRoute>() {
+            extensions.insert(route.clone());
+        }
+        if let Some(params) = req.extensions().get::<PathParams>() {
+            extensions.insert(params.clone());
+        }
+        if let Some(id) = req.extensions().get::<RequestId>() {
+            extensions.insert(id.clone());
+        }
+        if let Some(deadline) = req.extensions().get::<Deadline>() {
+            extensions.insert(*deadline);
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(cert) = req.extensions().get::<ClientCert>() {
+                extensions.insert(cert.clone());
+            }
+        }
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            extensions,
+        }
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// address of the client, `None` if the request did not come from a listener of `Server`
+    pub fn peer_addr(&self) -> Option<&PeerAddr> {
+        self.extensions.get()
+    }
+
+    /// route which matched the request, as registered on `Routes`
+    pub fn route(&self) -> Option<&str> {
+        self.extensions
+            .get::<MatchedRoute>()
+            .map(|route| route.0.as_str())
+    }
+
+    /// id of the request, from `X-Request-Id` header or generated by `Server`
+    pub fn request_id(&self) -> Option<&str> {
+        self.extensions.get::<RequestId>().map(|id| id.id.as_str())
+    }
+
+    /// time by which the route should reply, see `Server::with_timeout`
+    pub fn deadline(&self) -> Option<Instant> {
+        self.extensions.get::<Deadline>().map(|deadline| deadline.0)
+    }
+
+    /// time left until `deadline`, zero if it has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        let deadline = self.deadline()?;
+        let now = Instant::now();
+        Some(if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        })
+    }
+
+    /// values captured from named path segments
+    pub fn path_params(&self) -> Option<&PathParams> {
+        self.extensions.get()
+    }
+
+    /// principal authenticated by `Auth`
+    pub fn principal<P>(&self) -> Option<&P>
+    where
+        P: Send + Sync + 'static,
+    {
+        self.extensions
+            .get::<Principal<P>>()
+            .map(|principal| &principal.0)
+    }
+
+    /// principal authenticated by `Auth`, `ErrorKind::Unauthorized` if there is none, e.g. the
+    /// route is not behind `Auth`. Handlers reply it as `401 Unauthorized` with `?`.
+    pub fn authenticated<P>(&self) -> Result<&P>
+    where
+        P: Send + Sync + 'static,
+    {
+        self.principal()
+            .ok_or_else(|| ErrorKind::Unauthorized.into())
+    }
+
+    /// subject of the verified client certificate, e.g. `CN=client, O=example`, see
+    /// `TlsConfig::client_ca`
+    #[cfg(feature = "tls")]
+    pub fn client_cert_subject(&self) -> Option<&str> {
+        self.extensions
+            .get::<ClientCert>()
+            .map(|cert| cert.subject.as_str())
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Body, StatusCode};
+    use serde_json::Value;
+
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use Empty;
+
+    /// replies the request id seen by the handler, `/fail` replies an error
+    fn client() -> TestClient {
+        let id = sync::serv_ctx(|ctx: &RequestContext, _req: Empty| -> Result<String> {
+            Ok(ctx.request_id().unwrap_or_default().to_owned())
+        });
+        let fail = sync::serv(|_req: Empty| -> Result<String> { bail!("failed") });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/id", id);
+        routes.push(Method::GET, "/fail", fail);
+        TestClient::new(Server::new(routes).with_request_id_in_errors())
+    }
+
+    fn get(path: &str, id: Option<&str>) -> TestResponse<String> {
+        let mut req = Request::get(path);
+        if let Some(id) = id {
+            req.header(REQUEST_ID, id);
+        }
+        client().request(req.body(Body::empty()).unwrap())
+    }
+
+    fn header<T>(resp: &TestResponse<T>) -> &str {
+        resp.headers()[REQUEST_ID].to_str().unwrap()
+    }
+
+    #[test]
+    fn generated_id_is_echoed() {
+        let resp = get("/id", None);
+        resp.assert_status(StatusCode::OK);
+        let id = header(&resp).to_owned();
+        assert!(valid_id(&id), "{}", id);
+        assert_eq!(resp.into_result(), id);
+        assert_ne!(header(&get("/id", None)), id);
+    }
+
+    #[test]
+    fn request_id_is_propagated() {
+        let resp = get("/id", Some("abc-1"));
+        assert_eq!(header(&resp), "abc-1");
+        assert_eq!(resp.into_result(), "abc-1");
+
+        let resp = get("/fail", Some("abc-2"));
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(header(&resp), "abc-2");
+        let reply: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(reply["request_id"], "abc-2");
+
+        let resp = get("/missing", Some("abc-3"));
+        resp.assert_status(StatusCode::NOT_FOUND);
+        let reply: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(reply["request_id"], "abc-3");
+    }
+
+    #[test]
+    fn malformed_id_is_replaced() {
+        let resp = get("/id", Some("a b"));
+        let id = header(&resp).to_owned();
+        assert_ne!(id, "a b");
+        assert_eq!(resp.into_result(), id);
+    }
+}
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use hyper::header::*;
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use error::*;
+
+#[derive(Clone, Debug)]
+enum AllowOrigin {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// CORS policy of `Server`, see `Server::with_cors`. Without a policy every response allows any
+/// origin and preflight requests are not answered.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: AllowOrigin,
+    /// empty to allow methods registered for the requested path
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    /// Allows any origin, methods registered for the requested path and no extra headers.
+    pub fn new() -> Self {
+        Self {
+            origins: AllowOrigin::Any,
+            methods: Vec::new(),
+            headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow `origin`, e.g. `https://example.com`. Once called, only listed origins are allowed.
+    /// Fails if `origin` is not a valid header value.
+    pub fn allow_origin(mut self, origin: &str) -> Result<Self> {
+        let origin = match HeaderValue::from_str(origin) {
+            Ok(origin) => origin,
+            Err(_) => bail!("invalid origin: {:?}", origin),
+        };
+        match self.origins {
+            AllowOrigin::Any => self.origins = AllowOrigin::List(vec![origin]),
+            AllowOrigin::List(ref mut origins) => origins.push(origin),
+        }
+        Ok(self)
+    }
+
+    /// Allow only `methods`, instead of methods registered for the requested path.
+    pub fn allow_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.methods.extend(methods);
+        self
+    }
+
+    /// Allow request headers, e.g. `Content-Type` or `Authorization`.
+    pub fn allow_headers<I>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Allow cookies and credentials from origins listed by `allow_origin`, which must be called
+    /// first. Fails if any origin is allowed, which would let every site make credentialed
+    /// requests.
+    pub fn allow_credentials(mut self, allow: bool) -> Result<Self> {
+        if let AllowOrigin::Any = self.origins {
+            if allow {
+                bail!("credentials require origins listed by Cors::allow_origin");
+            }
+        }
+        self.credentials = allow;
+        Ok(self)
+    }
+
+    /// How long preflight responses can be cached.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// `Access-Control-Allow-Origin` for `origin`, `None` if the origin is not allowed
+    fn origin_value(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match self.origins {
+            AllowOrigin::Any => Some(HeaderValue::from_static("*")),
+            AllowOrigin::List(ref origins) => origin.filter(|o| origins.contains(o)).cloned(),
+        }
+    }
+
+    /// whether the reply depends on the `Origin` header
+    fn vary_origin(&self) -> bool {
+        match self.origins {
+            AllowOrigin::Any => false,
+            AllowOrigin::List(_) => true,
+        }
+    }
+
+    pub(crate) fn is_preflight(req: &Request<Body>) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(ORIGIN)
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// reply preflight `req`, `registered` are methods of the routes for the requested path.
+    /// Disallowed origins and methods get no `Access-Control-Allow-*` headers.
+    pub(crate) fn preflight(&self, req: &Request<Body>, registered: Vec<Method>) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        let methods = if self.methods.is_empty() {
+            registered
+        } else {
+            self.methods.clone()
+        };
+        let requested = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+        if !requested.is_some_and(|method| methods.contains(&method)) {
+            if self.vary_origin() {
+                resp.headers_mut()
+                    .insert(VARY, HeaderValue::from_static("Origin"));
+            }
+            return resp;
+        }
+        self.apply(req.headers().get(ORIGIN), &mut resp);
+        if !resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return resp;
+        }
+
+        let methods = methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let headers = resp.headers_mut();
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if !self.headers.is_empty() {
+            let allowed = self
+                .headers
+                .iter()
+                .map(|h| h.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(allowed) = HeaderValue::from_str(&allowed) {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+        resp
+    }
+
+    /// replace default CORS headers of `resp` for request `origin`
+    pub(crate) fn apply(&self, origin: Option<&HeaderValue>, resp: &mut Response<Body>) {
+        let headers = resp.headers_mut();
+        headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+        if self.vary_origin() {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if let Some(origin) = self.origin_value(origin) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            if self.credentials {
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use error::Result;
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use Empty;
+
+    fn client(cors: Cors) -> TestClient {
+        let mut routes = Routes::new();
+        routes.push(
+            Method::GET,
+            "/items",
+            sync::serv(|_req: Empty| -> Result<u32> { Ok(1) }),
+        );
+        routes.push(
+            Method::POST,
+            "/items",
+            sync::serv(|_req: Empty| -> Result<u32> { Ok(2) }),
+        );
+        TestClient::new(Server::new(routes).with_cors(cors))
+    }
+
+    fn preflight(client: &mut TestClient, origin: &str, method: &str) -> TestResponse<()> {
+        let req = Request::options("/items")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .body(Body::empty())
+            .unwrap();
+        client.request(req)
+    }
+
+    #[test]
+    fn preflight_allows_registered_methods() {
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .unwrap()
+            .allow_headers(vec![CONTENT_TYPE])
+            .max_age(Duration::from_secs(600));
+        let mut client = client(cors);
+        let resp = preflight(&mut client, "https://example.com", "POST");
+        resp.assert_status(StatusCode::NO_CONTENT);
+        let headers = resp.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        let methods = headers[ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap();
+        let mut methods = methods.split(", ").collect::<Vec<_>>();
+        methods.sort();
+        assert_eq!(methods, vec!["GET", "POST"]);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[VARY], "Origin");
+    }
+
+    #[test]
+    fn other_origins_are_not_allowed() {
+        let mut client = client(Cors::new().allow_origin("https://example.com").unwrap());
+        let resp = preflight(&mut client, "https://evil.example", "POST");
+        resp.assert_status(StatusCode::NO_CONTENT);
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+        let req = Request::get("/items")
+            .header(ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<u32> = client.request(req);
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(resp.into_result(), 1);
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .unwrap()
+            .allow_methods(vec![Method::GET]);
+        let mut client = client(cors);
+        let resp = preflight(&mut client, "https://example.com", "POST");
+        resp.assert_status(StatusCode::NO_CONTENT);
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+        let resp = preflight(&mut client, "https://example.com", "GET");
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET");
+    }
+
+    #[test]
+    fn invalid_origins_are_rejected() {
+        assert!(Cors::new().allow_origin("https://example.com\n").is_err());
+    }
+
+    #[test]
+    fn credentials_require_listed_origins() {
+        assert!(Cors::new().allow_credentials(true).is_err());
+
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .and_then(|cors| cors.allow_credentials(true))
+            .unwrap();
+        let mut client = client(cors);
+        let req = Request::get("/items")
+            .header(ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<u32> = client.request(req);
+        let headers = resp.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[VARY], "Origin");
+
+        let req = Request::get("/items")
+            .header(ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let resp: TestResponse<u32> = client.request(req);
+        assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!resp
+            .headers()
+            .contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+}
@@ -1,24 +1,48 @@
+extern crate base64;
+#[cfg(feature = "compression")]
+extern crate brotli;
 extern crate bytes;
 #[macro_use]
 extern crate error_chain;
+#[cfg(feature = "compression")]
+extern crate flate2;
 extern crate futures;
 extern crate hyper;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "openapi")]
+extern crate schemars;
 extern crate serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
 #[macro_use]
 extern crate serde_derive;
 #[cfg(feature = "fst")]
 extern crate fst;
 extern crate http;
+#[cfg(feature = "tls")]
+extern crate rustls;
 extern crate serde_json;
 extern crate serde_qs;
+#[cfg(feature = "ws")]
+extern crate sha1;
 extern crate tokio;
 extern crate tokio_current_thread;
+#[cfg_attr(feature = "tls", macro_use)]
 extern crate tokio_io;
 #[cfg(feature = "uds")]
 extern crate tokio_uds;
+#[cfg(feature = "ws")]
+extern crate tungstenite;
 extern crate url;
+#[cfg(feature = "tls")]
+extern crate webpki;
+#[cfg(feature = "tls")]
+extern crate x509_parser;
+#[cfg(feature = "compression")]
+extern crate zstd;
 
 pub mod error {
     use super::*;
@@ -46,18 +70,121 @@ pub mod error {
             DecodeQs(e: serde_qs::Error) {
                 description("badarg")
             }
+            DecodeBody(e: String) {
+                description("badarg")
+            }
+            EncodeBody(e: String) {
+                description("internal")
+            }
+            ShuttingDown {
+                description("shutting_down")
+            }
+            BodyTooLarge(limit: usize) {
+                description("body_too_large")
+            }
+            Timeout {
+                description("timeout")
+            }
+            RateLimited {
+                description("rate_limited")
+            }
+            Unauthorized {
+                description("unauthorized")
+            }
+            UnsupportedEncoding(coding: String) {
+                description("unsupported_encoding")
+            }
+            Spawn(e: String) {
+                description("internal")
+            }
+        }
+    }
+
+    /// Error which knows the HTTP status code it should be replied with.
+    pub trait ServError {
+        fn status(&self) -> hyper::StatusCode {
+            hyper::StatusCode::BAD_REQUEST
         }
     }
+
+    impl ServError for ErrorKind {
+        fn status(&self) -> hyper::StatusCode {
+            use hyper::StatusCode;
+            match self {
+                ErrorKind::UnexpectedMethod(_) => StatusCode::METHOD_NOT_ALLOWED,
+                ErrorKind::InvalidEndpoint => StatusCode::NOT_FOUND,
+                ErrorKind::DecodeJson(_) | ErrorKind::DecodeQs(_) | ErrorKind::DecodeBody(_) => {
+                    StatusCode::BAD_REQUEST
+                }
+                ErrorKind::EncodeJson(_) | ErrorKind::EncodeBody(_) | ErrorKind::Spawn(_) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                ErrorKind::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+                ErrorKind::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                ErrorKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+                ErrorKind::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                ErrorKind::Hyper(_) | ErrorKind::Http(_) | ErrorKind::Io(_) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                _ => StatusCode::BAD_REQUEST,
+            }
+        }
+    }
+
+    impl ServError for Error {
+        fn status(&self) -> hyper::StatusCode {
+            self.kind().status()
+        }
+    }
+
+    /// Error of handlers, `Error` or an error of the application which converts from it.
+    /// Implemented for every type with these bounds.
+    pub trait HandlerError:
+        From<Error> + ServError + std::fmt::Debug + std::error::Error + 'static
+    {
+    }
+
+    impl<E> HandlerError for E where
+        E: From<Error> + ServError + std::fmt::Debug + std::error::Error + 'static
+    {
+    }
 }
 
 type SyncObj<T> = std::rc::Rc<T>;
 
 pub mod async;
+pub mod auth;
+pub mod client;
+pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod conn;
+pub mod context;
+pub mod cors;
+pub mod metrics;
+pub mod middleware;
+pub mod ndjson;
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod path;
+pub mod ratelimit;
 pub mod reply;
 pub mod server;
+pub mod sse;
+mod stream;
 pub mod sync;
+pub mod test;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "ws")]
+pub mod ws;
 
-pub use error::{Error, ErrorKind};
+pub use codec::Codec;
+pub use context::RequestContext;
+pub use error::{Error, ErrorKind, HandlerError, ServError};
+pub use reply::{IntoResponse, Response};
 pub use server::Server;
 use std::fmt::Debug;
 
@@ -65,74 +192,171 @@ use futures::future::*;
 use futures::*;
 use hyper::header::*;
 use hyper::service::Service;
-use hyper::{Body, Request, Response};
+use hyper::{Body, Request};
+
+#[cfg(feature = "compression")]
+use compression::decode as decode_coding;
 
-pub fn resp_err() -> Response<Body> {
-    Response::builder()
+pub fn resp_err() -> hyper::Response<Body> {
+    hyper::Response::builder()
         .status(hyper::StatusCode::BAD_REQUEST)
         .body(Body::empty())
-        .unwrap_or_else(|_| Response::new(Body::empty()))
+        .unwrap_or_else(|_| hyper::Response::new(Body::empty()))
 }
 
-pub fn resp_serv_err<E>(e: E, status: hyper::StatusCode) -> Response<Body>
+pub fn resp_serv_err<E>(e: E, status: hyper::StatusCode) -> hyper::Response<Body>
+where
+    E: Debug + std::error::Error,
+{
+    resp_serv_err_as(e, status, Codec::Json)
+}
+
+/// error reply of `e` with `status`, encoded with `codec`
+pub fn resp_serv_err_as<E>(e: E, status: hyper::StatusCode, codec: Codec) -> hyper::Response<Body>
 where
     E: Debug + std::error::Error,
 {
     let reply = reply::ServiceReply::<(), E>::from(e);
-    let encoded = match serde_json::to_vec(&reply) {
+    let encoded = match codec.encode(&reply) {
         Ok(v) => v,
         Err(_e) => return resp_err(),
     };
 
-    Response::builder()
+    let mut builder = hyper::Response::builder();
+    if let reply::ServiceReply::Err { reason, .. } = reply {
+        builder.extension(reply::ErrorReason(reason));
+    }
+    builder
         .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(CONTENT_TYPE, codec.content_type())
         .status(status)
         .body(Body::from(encoded))
-        .unwrap_or_else(|_| Response::new(Body::empty()))
+        .unwrap_or_else(|_| hyper::Response::new(Body::empty()))
 }
 
-pub type HyperFuture = Box<Future<Item = Response<Body>, Error = hyper::Error>>;
+pub type HyperFuture = Box<Future<Item = hyper::Response<Body>, Error = hyper::Error>>;
+pub type HyperFutureSend = Box<Future<Item = hyper::Response<Body>, Error = hyper::Error> + Send>;
 pub type HyperService =
     Box<Service<ReqBody = Body, ResBody = Body, Error = hyper::Error, Future = HyperFuture>>;
 pub type HyperServiceSend = Box<
-    Service<
-        ReqBody = Body,
-        ResBody = Body,
-        Error = hyper::Error,
-        Future = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>,
-    >,
+    Service<ReqBody = Body, ResBody = Body, Error = hyper::Error, Future = HyperFutureSend> + Send,
 >;
+pub type HyperServiceSync = Box<ServiceSync>;
 
-/// parse API req from qs/body
-fn parse_req<R>(req: Request<Body>) -> Box<Future<Item = R, Error = Error>>
+/// Service of routes which are served from multiple threads, see `Server::run_threaded`.
+/// `Server` calls a clone of the route's service for each request instead of sharing it, so
+/// it is implemented for `Clone` services which are `Send` and `Sync`.
+pub trait ServiceSync:
+    Service<ReqBody = Body, ResBody = Body, Error = hyper::Error, Future = HyperFutureSend>
+    + Send
+    + Sync
+{
+    fn box_clone(&self) -> HyperServiceSync;
+}
+
+impl<T> ServiceSync for T
 where
-    R: for<'de> serde::Deserialize<'de> + 'static,
+    T: Service<ReqBody = Body, ResBody = Body, Error = hyper::Error, Future = HyperFutureSend>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
 {
+    fn box_clone(&self) -> HyperServiceSync {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for HyperServiceSync {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Request body size limit when neither route nor `Server` sets one.
+pub const DEFAULT_BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Body size limit of the routed request, stored in request extensions by `Server`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BodyLimit(pub(crate) usize);
+
+/// API req before decoding, see `parse_req`
+struct RawReq {
+    ctx: RequestContext,
+    body: Vec<u8>,
+}
+
+impl RawReq {
+    /// decode API req from qs/body
+    fn decode<R>(self) -> Result<(RequestContext, R), Error>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        use hyper::Method;
+        let req = {
+            let ctx = &self.ctx;
+            let params = ctx.path_params();
+            match *ctx.method() {
+                Method::GET | Method::DELETE => {
+                    let qs = ctx.uri().query().unwrap_or("");
+                    match params {
+                        Some(params) => serde_qs::from_str(&params.merge_qs(qs)),
+                        None => serde_qs::from_str(qs),
+                    }
+                    .map_err(|e| Error::from(ErrorKind::DecodeQs(e)))
+                }
+                _ => {
+                    let codec = Codec::request(ctx.headers())?;
+                    match params {
+                        Some(params) => params.merge(codec.decode(&self.body)?),
+                        None => codec.decode(&self.body),
+                    }
+                }
+            }
+        }?;
+        Ok((self.ctx, req))
+    }
+}
+
+/// read API req from qs/body
+fn read_req(req: Request<Body>) -> Box<Future<Item = RawReq, Error = Error> + Send> {
     use hyper::Method;
-    match req.method().clone() {
-        Method::GET | Method::DELETE => {
-            let qs = req.uri().query().unwrap_or("");
-            let req: Result<R, Error> =
-                serde_qs::from_str(qs).map_err(|e| ErrorKind::DecodeQs(e).into());
-            Box::new(result(req))
-        }
-        Method::PUT | Method::POST => {
+    let (ctx, body) = RequestContext::from_request(req);
+    let limit = ctx
+        .extensions()
+        .get::<BodyLimit>()
+        .map(|limit| limit.0)
+        .unwrap_or(DEFAULT_BODY_LIMIT);
+    match ctx.method().clone() {
+        Method::GET | Method::DELETE => Box::new(ok(RawReq {
+            ctx,
+            body: Vec::new(),
+        })),
+        Method::PUT | Method::POST | Method::PATCH => {
+            // reject before reading the body if the client tells its length
+            let len = ctx
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok())
+                .and_then(|len| len.parse::<u64>().ok());
+            if len.is_some_and(|len| len > limit as u64) {
+                return Box::new(err(ErrorKind::BodyTooLarge(limit).into()));
+            }
+
             let buf = Vec::new();
 
-            let f = req
-                .into_body()
+            let f = body
                 .map_err(Error::from)
-                .fold(buf, |mut buf, chunk| {
-                    buf.extend_from_slice(&chunk);
-                    //TODO: move to config?
-                    if buf.len() > 1024 * 1024 * 4 {
-                        Err(Error::from("body too large"))
-                    } else {
-                        Ok(buf)
+                .fold(buf, move |mut buf, chunk| {
+                    if buf.len() + chunk.len() > limit {
+                        return Err(Error::from(ErrorKind::BodyTooLarge(limit)));
                     }
+                    buf.extend_from_slice(&chunk);
+                    Ok(buf)
                 })
-                .and_then(move |chunk: Vec<u8>| {
-                    serde_json::from_slice(&chunk).map_err(|e| ErrorKind::DecodeJson(e).into())
+                .and_then(move |body| {
+                    let body = decode_body(ctx.headers(), body, limit)?;
+                    Ok(RawReq { ctx, body })
                 });
             Box::new(f)
         }
@@ -140,5 +364,189 @@ where
     }
 }
 
+/// decode `body` by `Content-Encoding` in `headers`, the decoded body is limited to `limit`
+fn decode_body(
+    headers: &hyper::HeaderMap,
+    mut body: Vec<u8>,
+    limit: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut codings = Vec::new();
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|e| ErrorKind::DecodeBody(e.to_string()))?;
+        for coding in value.split(',') {
+            let coding = coding.trim().to_ascii_lowercase();
+            if !coding.is_empty() && coding != "identity" {
+                codings.push(coding);
+            }
+        }
+    }
+    // codings are listed in the order they were applied
+    for coding in codings.iter().rev() {
+        body = decode_coding(coding, &body, limit)?;
+    }
+    Ok(body)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decode_coding(coding: &str, _body: &[u8], _limit: usize) -> Result<Vec<u8>, Error> {
+    bail!(ErrorKind::UnsupportedEncoding(coding.to_owned()))
+}
+
+/// parse API req from qs/body
+fn parse_req<R>(req: Request<Body>) -> Box<Future<Item = (RequestContext, R), Error = Error>>
+where
+    R: for<'de> serde::Deserialize<'de> + 'static,
+{
+    Box::new(read_req(req).and_then(RawReq::decode))
+}
+
+/// parse API req from qs/body, for `Send` services
+fn parse_req_send<R>(
+    req: Request<Body>,
+) -> Box<Future<Item = (RequestContext, R), Error = Error> + Send>
+where
+    R: for<'de> serde::Deserialize<'de> + Send + 'static,
+{
+    Box::new(read_req(req).and_then(RawReq::decode))
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
 pub struct Empty {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt;
+
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use test::TestClient;
+
+    #[test]
+    fn error_kinds_map_to_status() {
+        let statuses = vec![
+            (
+                ErrorKind::UnexpectedMethod(Method::PUT),
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (ErrorKind::InvalidEndpoint, StatusCode::NOT_FOUND),
+            (ErrorKind::DecodeBody("".into()), StatusCode::BAD_REQUEST),
+            (
+                ErrorKind::EncodeBody("".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (ErrorKind::ShuttingDown, StatusCode::SERVICE_UNAVAILABLE),
+            (ErrorKind::BodyTooLarge(1), StatusCode::PAYLOAD_TOO_LARGE),
+            (ErrorKind::Timeout, StatusCode::GATEWAY_TIMEOUT),
+            (ErrorKind::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (ErrorKind::Unauthorized, StatusCode::UNAUTHORIZED),
+            (
+                ErrorKind::UnsupportedEncoding("".into()),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                ErrorKind::Spawn("".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (ErrorKind::Msg("failed".into()), StatusCode::BAD_REQUEST),
+        ];
+        for (kind, status) in statuses {
+            let e = Error::from(kind);
+            assert_eq!(e.status(), status, "{:?}", e);
+        }
+    }
+
+    /// application error, `Overflow` overrides the status
+    #[derive(Debug)]
+    enum AppError {
+        Serv(Error),
+        Overflow,
+    }
+
+    impl From<Error> for AppError {
+        fn from(e: Error) -> Self {
+            AppError::Serv(e)
+        }
+    }
+
+    impl fmt::Display for AppError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                AppError::Serv(e) => fmt::Display::fmt(e, f),
+                AppError::Overflow => f.write_str("overflow"),
+            }
+        }
+    }
+
+    impl std::error::Error for AppError {}
+
+    impl ServError for AppError {
+        fn status(&self) -> hyper::StatusCode {
+            match self {
+                AppError::Serv(e) => e.status(),
+                AppError::Overflow => StatusCode::UNPROCESSABLE_ENTITY,
+            }
+        }
+    }
+
+    /// application error with the default status
+    #[derive(Debug)]
+    struct PlainError(Error);
+
+    impl From<Error> for PlainError {
+        fn from(e: Error) -> Self {
+            PlainError(e)
+        }
+    }
+
+    impl fmt::Display for PlainError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Display::fmt(&self.0, f)
+        }
+    }
+
+    impl std::error::Error for PlainError {}
+
+    impl ServError for PlainError {}
+
+    #[derive(Deserialize, Serialize)]
+    struct AddReq {
+        a: i8,
+        b: i8,
+    }
+
+    fn client() -> TestClient {
+        let add = sync::serv(|req: AddReq| -> Result<i8, AppError> {
+            req.a.checked_add(req.b).ok_or(AppError::Overflow)
+        });
+        let plain = sync::serv(|_req: Empty| -> Result<(), PlainError> {
+            Err(PlainError(ErrorKind::Timeout.into()))
+        });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/add", add);
+        routes.push(Method::GET, "/plain", plain);
+        TestClient::new(Server::new(routes))
+    }
+
+    #[test]
+    fn handler_errors_are_replied_with_their_status() {
+        let mut client = client();
+        let resp = client.get::<_, i8>("/add", &AddReq { a: 1, b: 2 });
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.into_result(), 3);
+
+        let resp = client.get::<_, i8>("/add", &AddReq { a: 127, b: 1 });
+        resp.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        // decode error of `serv`, converted to the application error
+        let resp = client.get::<_, i8>("/add", &Empty {});
+        resp.assert_status(StatusCode::BAD_REQUEST);
+
+        // the default status ignores the wrapped error
+        let resp = client.get::<_, ()>("/plain", &Empty {});
+        resp.assert_status(StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::future::*;
+use hyper;
+use hyper::header::*;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+
+use reply::ErrorReason;
+use HyperFutureSend;
+use HyperServiceSync;
+
+/// Upper bounds of latency histogram buckets, in seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Registry of per-route metrics, keyed by method and route pattern. Register it with
+/// `Server::with_metrics`, and serve it with `Routes::push_send(GET, path, metrics.service())`.
+/// Requests which match no route are recorded with an empty route, and with `other` method
+/// unless the method is one of RFC 7231 or `PATCH`, so that clients can not add labels.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    buckets: Vec<f64>,
+    routes: BTreeMap<(String, String), RouteMetrics>,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    /// requests by status code
+    requests: BTreeMap<u16, u64>,
+    /// error replies by reason
+    errors: BTreeMap<String, u64>,
+    in_flight: i64,
+    /// cumulative counts of `Registry::buckets`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::with_buckets(DEFAULT_BUCKETS)
+    }
+
+    /// Metrics with latency histogram `buckets`, upper bounds in seconds.
+    pub fn with_buckets(buckets: &[f64]) -> Self {
+        let registry = Registry {
+            buckets: buckets.to_vec(),
+            routes: BTreeMap::new(),
+        };
+        Self {
+            inner: Arc::new(Mutex::new(registry)),
+        }
+    }
+
+    /// Service which replies metrics in Prometheus text format.
+    pub fn service(&self) -> HyperServiceSync {
+        Box::new(self.clone())
+    }
+
+    /// Metrics in Prometheus text format.
+    pub fn encode(&self) -> String {
+        let registry = self.inner.lock().expect("poisoned");
+        registry.encode()
+    }
+
+    /// count `method` `route` as in flight until the returned timer is dropped
+    pub(crate) fn start(&self, method: &hyper::Method, route: &str) -> Timer {
+        let method = if route.is_empty() {
+            method_label(method)
+        } else {
+            method.as_str()
+        };
+        let key = (method.to_owned(), route.to_owned());
+        let mut registry = self.inner.lock().expect("poisoned");
+        registry.route(&key).in_flight += 1;
+        Timer {
+            metrics: self.clone(),
+            key,
+            start: Instant::now(),
+        }
+    }
+}
+
+/// Request in flight, see `Metrics::start`.
+pub(crate) struct Timer {
+    metrics: Metrics,
+    key: (String, String),
+    start: Instant,
+}
+
+impl Timer {
+    /// record response of `f`. Latency is measured until the response head is ready, streamed
+    /// bodies are not included.
+    pub(crate) fn observe<F>(self, f: F) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+    where
+        F: Future<Item = Response<Body>, Error = hyper::Error>,
+    {
+        f.map(move |resp| {
+            let reason = resp.extensions().get::<ErrorReason>();
+            self.finish(resp.status(), reason.map(|reason| reason.0.as_str()));
+            resp
+        })
+    }
+
+    fn finish(&self, status: hyper::StatusCode, reason: Option<&str>) {
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+        let mut registry = self.metrics.inner.lock().expect("poisoned");
+        let bounds = registry.buckets.clone();
+        let route = registry.route(&self.key);
+        *route.requests.entry(status.as_u16()).or_insert(0) += 1;
+        if let Some(reason) = reason {
+            *route.errors.entry(reason.to_owned()).or_insert(0) += 1;
+        }
+        for (count, bound) in route.buckets.iter_mut().zip(bounds) {
+            if secs <= bound {
+                *count += 1;
+            }
+        }
+        route.sum += secs;
+        route.count += 1;
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // also on cancelled requests, e.g. when the client goes away
+        if let Ok(mut registry) = self.metrics.inner.lock() {
+            registry.route(&self.key).in_flight -= 1;
+        }
+    }
+}
+
+impl Registry {
+    fn route(&mut self, key: &(String, String)) -> &mut RouteMetrics {
+        let buckets = self.buckets.len();
+        self.routes
+            .entry(key.clone())
+            .or_insert_with(|| RouteMetrics {
+                buckets: vec![0; buckets],
+                ..RouteMetrics::default()
+            })
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "serv_requests_total",
+            "counter",
+            "Requests by method, route and status.",
+        );
+        for ((method, route), metrics) in &self.routes {
+            for (status, count) in &metrics.requests {
+                let _ = writeln!(
+                    out,
+                    "serv_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    escape(method),
+                    escape(route),
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "serv_errors_total",
+            "counter",
+            "Error replies by method, route and reason.",
+        );
+        for ((method, route), metrics) in &self.routes {
+            for (reason, count) in &metrics.errors {
+                let _ = writeln!(
+                    out,
+                    "serv_errors_total{{method=\"{}\",route=\"{}\",reason=\"{}\"}} {}",
+                    escape(method),
+                    escape(route),
+                    escape(reason),
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "serv_requests_in_flight",
+            "gauge",
+            "Requests being handled by method and route.",
+        );
+        for ((method, route), metrics) in &self.routes {
+            let _ = writeln!(
+                out,
+                "serv_requests_in_flight{{method=\"{}\",route=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                metrics.in_flight
+            );
+        }
+
+        header(
+            &mut out,
+            "serv_request_duration_seconds",
+            "histogram",
+            "Time until the response head is ready, by method and route.",
+        );
+        for ((method, route), metrics) in &self.routes {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            for (bound, count) in self.buckets.iter().zip(&metrics.buckets) {
+                let _ = writeln!(
+                    out,
+                    "serv_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "serv_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, metrics.count
+            );
+            let _ = writeln!(
+                out,
+                "serv_request_duration_seconds_sum{{{}}} {}",
+                labels, metrics.sum
+            );
+            let _ = writeln!(
+                out,
+                "serv_request_duration_seconds_count{{{}}} {}",
+                labels, metrics.count
+            );
+        }
+        out
+    }
+}
+
+/// `method` if it is standard, `other` otherwise
+fn method_label(method: &hyper::Method) -> &str {
+    use hyper::Method;
+    match *method {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::CONNECT
+        | Method::OPTIONS
+        | Method::TRACE
+        | Method::PATCH => method.as_str(),
+        _ => "other",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// escape label value
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Service for Metrics {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, _req: Request<Body>) -> Self::Future {
+        let resp = hyper::Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(self.encode()))
+            .unwrap_or_else(|_| hyper::Response::new(Body::empty()));
+        Box::new(ok(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use hyper::{Method, StatusCode};
+
+    use error::Result;
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use Empty;
+
+    #[test]
+    fn latency_is_counted_in_buckets() {
+        let metrics = Metrics::with_buckets(&[0.5, 10.0]);
+        let mut timer = metrics.start(&Method::GET, "/items");
+        timer.start -= Duration::from_secs(1);
+        timer.finish(StatusCode::OK, None);
+        drop(timer);
+
+        let encoded = metrics.encode();
+        let labels = "method=\"GET\",route=\"/items\"";
+        for line in &[
+            format!("serv_requests_total{{{},status=\"200\"}} 1", labels),
+            format!("serv_requests_in_flight{{{}}} 0", labels),
+            format!(
+                "serv_request_duration_seconds_bucket{{{},le=\"0.5\"}} 0",
+                labels
+            ),
+            format!(
+                "serv_request_duration_seconds_bucket{{{},le=\"10\"}} 1",
+                labels
+            ),
+            format!(
+                "serv_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 1",
+                labels
+            ),
+            format!("serv_request_duration_seconds_count{{{}}} 1", labels),
+        ] {
+            assert!(
+                encoded.lines().any(|l| l == line),
+                "no {} in\n{}",
+                line,
+                encoded
+            );
+        }
+        assert!(encoded.contains("# TYPE serv_request_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn unknown_methods_are_other() {
+        let metrics = Metrics::new();
+        let mut routes = Routes::new();
+        routes.push(
+            Method::GET,
+            "/items",
+            sync::serv(|_req: Empty| -> Result<u32> { Ok(1) }),
+        );
+        routes.push_send(Method::GET, "/metrics", metrics.service());
+        let mut client = TestClient::new(Server::new(routes).with_metrics(metrics));
+
+        let resp: TestResponse<u32> = client.get("/items", &Empty {});
+        assert_eq!(resp.into_result(), 1);
+        for method in &["PURGE", "BREW"] {
+            let req = Request::builder()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri("/items")
+                .body(Body::empty())
+                .unwrap();
+            let _: TestResponse<()> = client.request(req);
+        }
+
+        let resp: TestResponse<()> = client.get("/metrics", &Empty {});
+        let encoded = String::from_utf8(resp.body().to_vec()).unwrap();
+        let requests = encoded
+            .lines()
+            .filter(|line| line.starts_with("serv_requests_total"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            requests,
+            vec![
+                "serv_requests_total{method=\"GET\",route=\"/items\",status=\"200\"} 1",
+                "serv_requests_total{method=\"other\",route=\"\",status=\"404\"} 2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use futures::future::*;
+use hyper;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+
+use context::RequestContext;
+use HyperFuture;
+use HyperFutureSend;
+use HyperService;
+use HyperServiceSync;
+
+/// Hook which runs around routes. Apply it to a single route with `layer` or `layer_send`, to
+/// a group of routes with `Routes::layer`, or to every request with `Server::with_middleware`.
+/// A middleware wraps everything applied before it, so the one applied last runs outermost:
+/// its `request` hook runs first and its `response` hook last.
+pub trait Middleware: Send + Sync {
+    /// Called before the route. Returning a response short-circuits the request: the route and
+    /// inner middlewares are skipped, `response` of outer middlewares still runs.
+    fn request(&self, _req: &mut Request<Body>) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Called with the response of the route. `ctx` is a copy of the request without body and
+    /// custom extensions.
+    fn response(&self, _ctx: &RequestContext, _resp: &mut Response<Body>) {}
+}
+
+/// `layer` applies `middleware` to `service` of a single route.
+pub fn layer<M>(middleware: M, service: HyperService) -> HyperService
+where
+    M: Middleware + 'static,
+{
+    Box::new(Layered::new(vec![Arc::new(middleware)], service))
+}
+
+/// `layer_send` applies `middleware` to `service` of a single route.
+pub fn layer_send<M>(middleware: M, service: HyperServiceSync) -> HyperServiceSync
+where
+    M: Middleware + 'static,
+{
+    Box::new(Layered::new(vec![Arc::new(middleware)], service))
+}
+
+/// Middlewares which `request` hook passed the request, see `enter`.
+pub(crate) struct Chain {
+    entered: Vec<Arc<Middleware>>,
+    ctx: Option<RequestContext>,
+}
+
+/// run `request` hooks of `middlewares` on `req`, returns the response if one short-circuits
+pub(crate) fn enter(
+    middlewares: &[Arc<Middleware>],
+    req: &mut Request<Body>,
+) -> (Chain, Option<Response<Body>>) {
+    let mut entered = Vec::with_capacity(middlewares.len());
+    let mut resp = None;
+    for middleware in middlewares {
+        resp = middleware.request(req);
+        if resp.is_some() {
+            break;
+        }
+        entered.push(middleware.clone());
+    }
+    let ctx = if middlewares.is_empty() {
+        None
+    } else {
+        Some(RequestContext::head(req))
+    };
+    (Chain { entered, ctx }, resp)
+}
+
+impl Chain {
+    /// run `response` hooks on the response of `f`, innermost first
+    pub(crate) fn leave<F>(self, f: F) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+    where
+        F: Future<Item = Response<Body>, Error = hyper::Error>,
+    {
+        let Chain { entered, ctx } = self;
+        f.map(move |mut resp| {
+            if let Some(ctx) = ctx {
+                for middleware in entered.iter().rev() {
+                    middleware.response(&ctx, &mut resp);
+                }
+            }
+            resp
+        })
+    }
+}
+
+/// Route service `S` with middlewares applied.
+#[derive(Clone)]
+pub(crate) struct Layered<S> {
+    middlewares: Vec<Arc<Middleware>>,
+    inner: S,
+}
+
+impl<S> Layered<S> {
+    pub(crate) fn new(middlewares: Vec<Arc<Middleware>>, inner: S) -> Self {
+        Self { middlewares, inner }
+    }
+}
+
+impl Service for Layered<HyperService> {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFuture;
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let (chain, resp) = enter(&self.middlewares, &mut req);
+        let f = match resp {
+            Some(resp) => Box::new(ok(resp)),
+            None => self.inner.call(req),
+        };
+        Box::new(chain.leave(f))
+    }
+}
+
+impl Service for Layered<HyperServiceSync> {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let (chain, resp) = enter(&self.middlewares, &mut req);
+        let f = match resp {
+            Some(resp) => Box::new(ok(resp)),
+            None => self.inner.call(req),
+        };
+        Box::new(chain.leave(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use hyper::Method;
+
+    use server::{Routes, Server};
+    use sync;
+    use test::TestClient;
+    use Empty;
+
+    /// appends its name to `x-trace` of requests and responses
+    struct Trace(&'static str);
+
+    impl Middleware for Trace {
+        fn request(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+            let name = HeaderValue::from_static(self.0);
+            req.headers_mut().append("x-trace", name);
+            None
+        }
+
+        fn response(&self, _ctx: &RequestContext, resp: &mut Response<Body>) {
+            let name = HeaderValue::from_static(self.0);
+            resp.headers_mut().append("x-trace", name);
+        }
+    }
+
+    fn trace(headers: &hyper::HeaderMap) -> Vec<&str> {
+        let trace = headers.get_all("x-trace").iter();
+        trace.map(|name| name.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn middleware_applied_last_runs_outermost() {
+        let mut routes = Routes::new();
+        let echo = sync::serv_ctx(|ctx: &RequestContext, _req: Empty| -> ::error::Result<_> {
+            Ok(trace(ctx.headers()).join(","))
+        });
+        routes.push(Method::GET, "/trace", echo);
+        routes.layer(Trace("route1"));
+        routes.layer(Trace("route2"));
+        let server = Server::new(routes)
+            .with_middleware(Trace("server1"))
+            .with_middleware(Trace("server2"));
+
+        let resp = TestClient::new(server).get::<_, String>("/trace", &Empty {});
+        assert_eq!(
+            trace(resp.headers()),
+            ["route1", "route2", "server1", "server2"]
+        );
+        assert_eq!(resp.into_result(), "server2,server1,route2,route1");
+    }
+}
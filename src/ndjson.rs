@@ -0,0 +1,289 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use hyper::Chunk;
+
+use super::*;
+use stream;
+
+/// `serv_state` builds `HyperService` which replies items of the stream returned by `F` as
+/// NDJSON lines in the reply envelope, e.g. `{"status":"ok","result":...}`. Lines are written
+/// as the client reads them. As the status is already sent, an error of the stream is replied
+/// as the last line, `{"status":"error",...}`.
+/// The stream is not `Send` and needs a current-thread executor, see `stream`.
+pub fn serv_state<F, S, Req, Resp, E>(state: S, f: F) -> HyperService
+where
+    F: for<'a> Fn(&'a S, Req) -> Box<Stream<Item = Resp, Error = E>> + 'static,
+    S: 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: serde::Serialize + 'static,
+    E: HandlerError,
+{
+    Box::new(NdjsonService::new(move |req| f(&state, req)))
+}
+
+/// `serv` builds `HyperService` which replies items of the stream returned by `F` as NDJSON
+/// lines. It runs on a current-thread executor, see `serv_state`.
+pub fn serv<F, Req, Resp, E>(f: F) -> HyperService
+where
+    F: Fn(Req) -> Box<Stream<Item = Resp, Error = E>> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: serde::Serialize + 'static,
+    E: HandlerError,
+{
+    Box::new(NdjsonService::new(f))
+}
+
+/// `serv_state_send` builds `HyperServiceSync` which replies items of the stream returned by
+/// `F` as NDJSON lines.
+pub fn serv_state_send<F, S, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a S, Req) -> Box<Stream<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: serde::Serialize + Send + 'static,
+    E: HandlerError + Send,
+{
+    Box::new(NdjsonServiceSend::new(move |req| f(&state, req)))
+}
+
+/// `serv_send` builds `HyperServiceSync` which replies items of the stream returned by `F` as
+/// NDJSON lines.
+pub fn serv_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: Fn(Req) -> Box<Stream<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: serde::Serialize + Send + 'static,
+    E: HandlerError + Send,
+{
+    Box::new(NdjsonServiceSend::new(f))
+}
+
+/// encode `reply` as a line
+fn encode_line<T, E>(reply: &reply::ServiceReply<T, E>) -> Result<Chunk, Error>
+where
+    T: serde::Serialize,
+{
+    let mut line = serde_json::to_vec(reply).map_err(ErrorKind::EncodeJson)?;
+    line.push(b'\n');
+    Ok(line.into())
+}
+
+/// Handler stream encoded as lines, which ends after an error line if the handler stream fails.
+struct Lines<St> {
+    inner: St,
+    done: bool,
+}
+
+impl<St> Lines<St> {
+    fn new(inner: St) -> Self {
+        Self { inner, done: false }
+    }
+}
+
+impl<St, E> Stream for Lines<St>
+where
+    St: Stream<Error = E>,
+    St::Item: serde::Serialize,
+    E: From<Error> + Debug + std::error::Error,
+{
+    type Item = Chunk;
+    type Error = std::io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, std::io::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let e = match self.inner.poll() {
+            Ok(Async::Ready(Some(item))) => {
+                let reply = reply::ServiceReply::<St::Item, E>::from(Ok(item));
+                match encode_line(&reply) {
+                    Ok(line) => return Ok(Async::Ready(Some(line))),
+                    Err(e) => E::from(e),
+                }
+            }
+            Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => e,
+        };
+        self.done = true;
+        let reply = reply::ServiceReply::<(), E>::from(e);
+        Ok(Async::Ready(encode_line(&reply).ok()))
+    }
+}
+
+fn ndjson_response(body: Body) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(body)
+        .unwrap_or_else(|e| resp_serv_err(e, hyper::StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// `NdjsonService` implements `hyper::service::Service` for streaming handler `F`
+struct NdjsonService<F, Req> {
+    f: SyncObj<F>,
+    _req: PhantomData<fn(Req)>,
+}
+
+impl<F, Req> NdjsonService<F, Req> {
+    fn new(f: F) -> Self {
+        Self {
+            f: SyncObj::new(f),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req, St, E> hyper::service::Service for NdjsonService<F, Req>
+where
+    F: Fn(Req) -> St + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    St: Stream<Error = E> + 'static,
+    St::Item: serde::Serialize,
+    E: HandlerError,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFuture;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let f = self.f.clone();
+        let f = parse_req(req).then(move |res| {
+            let body = res.and_then(|(_ctx, req)| stream::body(Lines::new(f(req))));
+            let resp = match body {
+                Ok(body) => ndjson_response(body),
+                Err(e) => {
+                    let e = E::from(e);
+                    let status = e.status();
+                    resp_serv_err_as(e, status, codec)
+                }
+            };
+            Ok(resp)
+        });
+        Box::new(f)
+    }
+}
+
+/// `NdjsonServiceSend` implements `hyper::service::Service` for `Send` and `Sync` streaming
+/// handler `F`
+struct NdjsonServiceSend<F, Req> {
+    f: Arc<F>,
+    _req: PhantomData<fn(Req)>,
+}
+
+impl<F, Req> NdjsonServiceSend<F, Req> {
+    fn new(f: F) -> Self {
+        Self {
+            f: Arc::new(f),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req> Clone for NdjsonServiceSend<F, Req> {
+    fn clone(&self) -> Self {
+        Self {
+            f: self.f.clone(),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req, St, E> hyper::service::Service for NdjsonServiceSend<F, Req>
+where
+    F: Fn(Req) -> St + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    St: Stream<Error = E> + Send + 'static,
+    St::Item: serde::Serialize,
+    E: HandlerError + Send,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let f = self.f.clone();
+        let f = parse_req_send(req).then(move |res| {
+            let resp = match res {
+                Ok((_ctx, req)) => ndjson_response(stream::body_send(Lines::new(f(req)))),
+                Err(e) => {
+                    let e = E::from(e);
+                    let status = e.status();
+                    resp_serv_err_as(e, status, codec)
+                }
+            };
+            Ok(resp)
+        });
+        Box::new(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::iter_result;
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use test::TestClient;
+    use Empty;
+
+    fn server() -> Server {
+        let lines = serv(|_req: Empty| {
+            let items = vec![Ok(1), Ok(2), Err(ErrorKind::InvalidEndpoint.into())];
+            Box::new(iter_result::<_, u32, Error>(items)) as Box<Stream<Item = _, Error = _>>
+        });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/lines", lines);
+        Server::new(routes)
+    }
+
+    #[test]
+    fn items_are_lines() {
+        let mut client = TestClient::new(server());
+        let resp = client.get::<_, ()>("/lines", &Empty {});
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/x-ndjson");
+        let lines: Vec<serde_json::Value> = resp
+            .body()
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["result"], 1);
+        assert_eq!(lines[1]["result"], 2);
+        assert_eq!(lines[2]["status"], "error");
+        assert_eq!(lines[2]["reason"], "invalid_endpoint");
+    }
+
+    #[test]
+    fn each_chunk_is_one_line() {
+        let items = vec![
+            Ok("a\nb".to_owned()),
+            Ok("\r\n".to_owned()),
+            Err(Error::from(ErrorKind::InvalidEndpoint)),
+            Ok("c".to_owned()),
+        ];
+        let chunks: Vec<Chunk> = Lines::new(iter_result(items)).collect().wait().unwrap();
+        let lines: Vec<serde_json::Value> = chunks
+            .iter()
+            .map(|chunk| {
+                let newlines = chunk.iter().filter(|b| **b == b'\n').count();
+                assert_eq!(newlines, 1, "{:?}", chunk);
+                assert!(chunk.ends_with(b"\n"), "{:?}", chunk);
+                serde_json::from_slice(chunk).unwrap()
+            })
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["result"], "a\nb");
+        assert_eq!(lines[1]["result"], "\r\n");
+        assert_eq!(lines[2]["reason"], "invalid_endpoint");
+    }
+}
@@ -0,0 +1,371 @@
+use bytes::Bytes;
+use futures::future::*;
+use hyper;
+use hyper::header::*;
+use hyper::service::Service;
+use hyper::{Body, Request};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use serde_json::{self, json, Map, Value};
+
+use HyperFutureSend;
+use HyperServiceSync;
+
+pub use schemars::JsonSchema;
+
+/// Description of a route for the OpenAPI document, see `Routes::document`.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    req: fn(&mut SchemaGenerator) -> Schema,
+    resp: fn(&mut SchemaGenerator) -> Schema,
+    summary: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    status: hyper::StatusCode,
+    errors: Vec<(hyper::StatusCode, String)>,
+}
+
+fn subschema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+impl Operation {
+    /// Operation of a handler with request `Req` and response `Resp`. For `Response<T>`
+    /// handlers, `Resp` is the body `T`.
+    pub fn new<Req, Resp>() -> Self
+    where
+        Req: JsonSchema,
+        Resp: JsonSchema,
+    {
+        Self {
+            req: subschema::<Req>,
+            resp: subschema::<Resp>,
+            summary: None,
+            description: None,
+            tags: Vec::new(),
+            status: hyper::StatusCode::OK,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn summary(mut self, summary: &str) -> Self {
+        self.summary = Some(summary.to_owned());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_owned());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_owned());
+        self
+    }
+
+    /// status of a successful reply, `200 OK` by default, e.g. `201 Created` of a `Response<T>`
+    pub fn status(mut self, status: hyper::StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// document error reply with `status`, other errors are documented as `default`
+    pub fn error(mut self, status: hyper::StatusCode, description: &str) -> Self {
+        self.errors.push((status, description.to_owned()));
+        self
+    }
+
+    fn to_json(&self, gen: &mut SchemaGenerator, method: &hyper::Method, path: &str) -> Value {
+        let mut op = Map::new();
+        if let Some(ref summary) = self.summary {
+            op.insert("summary".into(), summary.as_str().into());
+        }
+        if let Some(ref description) = self.description {
+            op.insert("description".into(), description.as_str().into());
+        }
+        if !self.tags.is_empty() {
+            op.insert("tags".into(), json_value(&self.tags));
+        }
+
+        let req = json_value(&(self.req)(gen));
+        let mut parameters: Vec<Value> = path_params(path)
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect();
+        match *method {
+            // decoded from query string, see `parse_req`
+            hyper::Method::GET | hyper::Method::DELETE => parameters.push(json!({
+                "name": "req",
+                "in": "query",
+                "style": "form",
+                "explode": true,
+                "schema": req,
+            })),
+            _ => {
+                let body = json!({
+                    "required": true,
+                    "content": { "application/json": { "schema": req } },
+                });
+                op.insert("requestBody".into(), body);
+            }
+        }
+        if !parameters.is_empty() {
+            op.insert("parameters".into(), Value::Array(parameters));
+        }
+
+        let result = json_value(&(self.resp)(gen));
+        let mut responses = Map::new();
+        responses.insert(
+            self.status.as_str().into(),
+            json!({
+                "description": self.status.canonical_reason().unwrap_or("ok"),
+                "content": { "application/json": { "schema": {
+                    "type": "object",
+                    "required": ["status", "result"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["ok"] },
+                        "result": result,
+                    },
+                } } },
+            }),
+        );
+        for (status, description) in &self.errors {
+            responses.insert(status.as_str().into(), error_response(description));
+        }
+        responses.insert("default".into(), error_response("error"));
+        op.insert("responses".into(), Value::Object(responses));
+        Value::Object(op)
+    }
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": {
+            "$ref": "#/components/schemas/ServiceError",
+        } } },
+    })
+}
+
+fn json_value<T: serde::Serialize>(v: &T) -> Value {
+    serde_json::to_value(v).unwrap_or(Value::Null)
+}
+
+/// names of `:name` segments of `path`
+fn path_params<'a>(path: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    path.split('/')
+        .filter(|s| s.starts_with(':'))
+        .map(|s| &s[1..])
+}
+
+/// `/users/:id` to `/users/{id}`
+fn openapi_path(path: &str) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|s| match s.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => s.to_owned(),
+        })
+        .collect();
+    segments.join("/")
+}
+
+/// Documented routes of `Routes`.
+#[derive(Clone, Default)]
+pub(crate) struct Docs {
+    operations: Vec<(hyper::Method, String, Operation)>,
+    /// path, title and version of the served document
+    serve: Option<(String, String, String)>,
+}
+
+impl Docs {
+    pub(crate) fn push(&mut self, method: hyper::Method, path: &str, op: Operation) {
+        self.operations.push((method, path.to_owned(), op));
+    }
+
+    pub(crate) fn serve(&mut self, path: &str, title: &str, version: &str) {
+        self.serve = Some((path.to_owned(), title.to_owned(), version.to_owned()));
+    }
+
+    pub(crate) fn extend(&mut self, other: Docs) {
+        self.operations.extend(other.operations);
+        if self.serve.is_none() {
+            self.serve = other.serve;
+        }
+    }
+
+    pub(crate) fn document(&self, title: &str, version: &str) -> Value {
+        let mut gen = SchemaGenerator::new(SchemaSettings::openapi3());
+        let mut paths = Map::new();
+        for (method, path, op) in &self.operations {
+            let op = op.to_json(&mut gen, method, path);
+            let item = paths
+                .entry(openapi_path(path))
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(ref mut item) = *item {
+                item.insert(method.as_str().to_lowercase(), op);
+            }
+        }
+
+        let mut schemas: Map<String, Value> = gen
+            .take_definitions()
+            .into_iter()
+            .map(|(name, schema)| (name, json_value(&schema)))
+            .collect();
+        schemas.insert(
+            "ServiceError".into(),
+            json!({
+                "type": "object",
+                "required": ["status", "reason"],
+                "properties": {
+                    "status": { "type": "string", "enum": ["error"] },
+                    "reason": { "type": "string" },
+                    "msg": { "type": "string" },
+                    "request_id": { "type": "string" },
+                },
+            }),
+        );
+
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": title, "version": version },
+            "paths": paths,
+            "components": { "schemas": schemas },
+        })
+    }
+
+    /// path and service which serves the document, if configured
+    pub(crate) fn service(&self) -> Option<(String, HyperServiceSync)> {
+        let (ref path, ref title, ref version) = *self.serve.as_ref()?;
+        let doc = serde_json::to_vec(&self.document(title, version)).ok()?;
+        Some((path.clone(), Box::new(Document(doc.into()))))
+    }
+}
+
+/// Service which replies the OpenAPI document as is, without reply envelope.
+#[derive(Clone)]
+struct Document(Bytes);
+
+impl Service for Document {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, _req: Request<Body>) -> Self::Future {
+        let resp = hyper::Response::builder()
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(self.0.clone()))
+            .unwrap_or_else(|_| hyper::Response::new(Body::empty()));
+        Box::new(ok(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use server::{Routes, Server};
+    use sync;
+    use test::TestClient;
+    use Empty;
+
+    #[derive(Deserialize, JsonSchema, Serialize)]
+    struct User {
+        name: String,
+    }
+
+    fn routes() -> Routes {
+        let create = sync::serv(|user: User| -> Result<String, ::Error> { Ok(user.name) });
+        let get = sync::serv(|_req: Empty| -> Result<String, ::Error> { Ok("bob".to_owned()) });
+        let mut routes = Routes::new();
+        routes.push(hyper::Method::POST, "/users", create);
+        routes.push(hyper::Method::GET, "/users/:id", get);
+        let create = Operation::new::<User, String>()
+            .summary("create user")
+            .tag("users")
+            .status(hyper::StatusCode::CREATED)
+            .error(hyper::StatusCode::CONFLICT, "user exists");
+        routes.document(hyper::Method::POST, "/users", create);
+        routes.document(
+            hyper::Method::GET,
+            "/users/:id",
+            Operation::new::<User, String>(),
+        );
+        routes
+    }
+
+    #[test]
+    fn document_describes_operations() {
+        let doc = routes().openapi("users", "1.0");
+        assert_eq!(doc["openapi"], "3.0.3");
+        assert_eq!(doc["info"], json!({ "title": "users", "version": "1.0" }));
+
+        let create = &doc["paths"]["/users"]["post"];
+        assert_eq!(create["summary"], "create user");
+        assert_eq!(create["tags"], json!(["users"]));
+        let body = &create["requestBody"]["content"]["application/json"]["schema"];
+        assert_eq!(body["$ref"], "#/components/schemas/User");
+        let user = &doc["components"]["schemas"]["User"];
+        assert_eq!(user["properties"]["name"]["type"], "string");
+
+        let get = &doc["paths"]["/users/{id}"]["get"];
+        assert!(get.get("requestBody").is_none());
+        let names: Vec<&Value> = get["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| &p["name"])
+            .collect();
+        assert_eq!(names, vec!["id", "req"]);
+        assert_eq!(get["parameters"][1]["in"], "query");
+    }
+
+    #[test]
+    fn declared_statuses_are_documented() {
+        let doc = routes().openapi("users", "1.0");
+        let error = json!({ "$ref": "#/components/schemas/ServiceError" });
+
+        let responses = doc["paths"]["/users"]["post"]["responses"]
+            .as_object()
+            .unwrap();
+        let statuses: Vec<&String> = responses.keys().collect();
+        assert_eq!(statuses, vec!["201", "409", "default"]);
+        let created = &responses["201"]["content"]["application/json"]["schema"];
+        assert_eq!(created["properties"]["result"]["type"], "string");
+        assert_eq!(responses["409"]["description"], "user exists");
+        assert_eq!(
+            responses["409"]["content"]["application/json"]["schema"],
+            error
+        );
+
+        let responses = doc["paths"]["/users/{id}"]["get"]["responses"]
+            .as_object()
+            .unwrap();
+        let statuses: Vec<&String> = responses.keys().collect();
+        assert_eq!(statuses, vec!["200", "default"]);
+        assert_eq!(
+            doc["components"]["schemas"]["ServiceError"]["required"],
+            json!(["status", "reason"])
+        );
+    }
+
+    #[test]
+    fn document_is_served() {
+        let mut routes = routes();
+        routes.serve_openapi("/openapi.json", "users", "1.0");
+        let expected = routes.openapi("users", "1.0");
+        let mut client = TestClient::new(Server::new(routes));
+        let resp = client.get::<_, Value>("/openapi.json", &Empty {});
+        resp.assert_status(hyper::StatusCode::OK);
+        let served: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(served, expected);
+    }
+}
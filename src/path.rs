@@ -0,0 +1,384 @@
+use std;
+
+use serde;
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde_json::{self, Map, Value};
+use serde_qs;
+use url;
+use url::form_urlencoded;
+
+use error::*;
+
+/// Route pattern with named segments, e.g. `/users/:id/posts/:post_id`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+impl PathPattern {
+    /// returns `true` if `path` contains any `:name` segment
+    pub fn is_pattern(path: &str) -> bool {
+        path.split('/').any(|s| s.starts_with(':'))
+    }
+
+    pub fn parse(path: &str) -> Self {
+        let segments = path
+            .split('/')
+            .map(|s| match s.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_owned()),
+                None => Segment::Static(s.to_owned()),
+            })
+            .collect();
+        Self { segments }
+    }
+
+    /// match `path` against the pattern, returning captured values on success
+    pub fn matches(&self, path: &str) -> Option<PathParams> {
+        let mut params = PathParams::default();
+        let mut parts = path.split('/');
+        for segment in &self.segments {
+            let part = parts.next()?;
+            match segment {
+                Segment::Static(s) => {
+                    if s != part {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    let value = url::percent_encoding::percent_decode(part.as_bytes())
+                        .decode_utf8_lossy()
+                        .into_owned();
+                    params.params.push((name.clone(), value));
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+impl std::fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                write!(f, "/")?;
+            }
+            match segment {
+                Segment::Static(s) => write!(f, "{}", s)?,
+                Segment::Param(name) => write!(f, ":{}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Values captured from named path segments.
+///
+/// `Server` stores matched params in request extensions. `parse_req` merges them into `Req`,
+/// and `PathParams::parse` deserializes them into a separate type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _v)| k == name)
+            .map(|(_k, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    /// deserialize captured values into `P`
+    pub fn parse<P>(&self) -> Result<P>
+    where
+        P: for<'de> serde::Deserialize<'de>,
+    {
+        serde_qs::from_str(&self.to_qs()).map_err(|e| ErrorKind::DecodeQs(e).into())
+    }
+
+    fn to_qs(&self) -> String {
+        form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(self.params.iter())
+            .finish()
+    }
+
+    /// merge captured values into query string `qs`. path params take precedence.
+    pub(crate) fn merge_qs(&self, qs: &str) -> String {
+        let mut out = self.to_qs();
+        for pair in qs.split('&') {
+            let key = pair.split('=').next().unwrap_or("");
+            if key.is_empty() || self.get(key).is_some() {
+                continue;
+            }
+            if !out.is_empty() {
+                out.push('&');
+            }
+            out.push_str(pair);
+        }
+        out
+    }
+
+    /// merge captured values into top-level fields of object `body`, decoded by any `Codec`
+    pub(crate) fn merge<R>(&self, body: Value) -> Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        let obj = match body {
+            Value::Object(obj) => obj,
+            v => return serde_json::from_value(v).map_err(|e| ErrorKind::DecodeJson(e).into()),
+        };
+        R::deserialize(MergedObject {
+            obj,
+            params: self.params.clone(),
+        })
+        .map_err(|e| ErrorKind::DecodeJson(e).into())
+    }
+}
+
+/// JSON object with path params merged in, path params take precedence.
+struct MergedObject {
+    obj: Map<String, Value>,
+    params: Vec<(String, String)>,
+}
+
+impl<'de> de::Deserializer<'de> for MergedObject {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let MergedObject { mut obj, params } = self;
+        for (name, _) in &params {
+            obj.remove(name);
+        }
+        let params = params.into_iter().map(|(k, v)| (k, MergedValue::Param(v)));
+        let body = obj.into_iter().map(|(k, v)| (k, MergedValue::Json(v)));
+        let mut map = de::value::MapDeserializer::new(params.chain(body));
+        let value = visitor.visit_map(&mut map)?;
+        map.end()?;
+        Ok(value)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+enum MergedValue {
+    Json(Value),
+    Param(String),
+}
+
+impl<'de> IntoDeserializer<'de, serde_json::Error> for MergedValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! merged_parse {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                match self {
+                    MergedValue::Json(v) => v.$method(visitor),
+                    MergedValue::Param(s) => match s.parse() {
+                        Ok(v) => visitor.$visit(v),
+                        Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &visitor)),
+                    },
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! merged_forward {
+    ($($method:ident($($arg:ident: $ty:ty),*),)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> std::result::Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                match self {
+                    MergedValue::Json(v) => v.$method($($arg,)* visitor),
+                    MergedValue::Param(s) => s.into_deserializer().$method($($arg,)* visitor),
+                }
+            }
+        )*
+    };
+}
+
+/// Path params are always strings, they are parsed on demand when `Req` asks for other types.
+impl<'de> de::Deserializer<'de> for MergedValue {
+    type Error = serde_json::Error;
+
+    merged_parse! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    merged_forward! {
+        deserialize_any(),
+        deserialize_str(),
+        deserialize_string(),
+        deserialize_bytes(),
+        deserialize_byte_buf(),
+        deserialize_unit(),
+        deserialize_unit_struct(name: &'static str),
+        deserialize_newtype_struct(name: &'static str),
+        deserialize_seq(),
+        deserialize_tuple(len: usize),
+        deserialize_tuple_struct(name: &'static str, len: usize),
+        deserialize_map(),
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]),
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]),
+        deserialize_identifier(),
+        deserialize_ignored_any(),
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            MergedValue::Json(v) => v.deserialize_option(visitor),
+            MergedValue::Param(s) => visitor.visit_some(MergedValue::Param(s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use sync;
+    use test::TestClient;
+    use Empty;
+
+    fn params(pairs: &[(&str, &str)]) -> PathParams {
+        let params = pairs
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        PathParams { params }
+    }
+
+    #[test]
+    fn pattern_captures_named_segments() {
+        assert!(PathPattern::is_pattern("/users/:id"));
+        assert!(!PathPattern::is_pattern("/users/id:1"));
+        let pattern = PathPattern::parse("/users/:id/posts/:post_id");
+        assert_eq!(pattern.to_string(), "/users/:id/posts/:post_id");
+        assert_eq!(
+            pattern.matches("/users/1/posts/2"),
+            Some(params(&[("id", "1"), ("post_id", "2")]))
+        );
+        assert_eq!(pattern.matches("/users/1/comments/2"), None);
+        assert_eq!(pattern.matches("/users/1/posts"), None);
+        assert_eq!(pattern.matches("/users/1/posts/2/likes"), None);
+        // segments are not optional
+        assert_eq!(pattern.matches("/users//posts/2"), None);
+    }
+
+    #[test]
+    fn trailing_slash_is_a_segment() {
+        let pattern = PathPattern::parse("/users/:id");
+        assert_eq!(pattern.matches("/users/1/"), None);
+        let pattern = PathPattern::parse("/users/:id/");
+        assert_eq!(pattern.matches("/users/1/"), Some(params(&[("id", "1")])));
+        assert_eq!(pattern.matches("/users/1"), None);
+    }
+
+    #[test]
+    fn params_are_percent_decoded() {
+        let pattern = PathPattern::parse("/files/:name");
+        let params = pattern.matches("/files/a%20b%2Fc%E2%9C%93").unwrap();
+        assert_eq!(params.get("name"), Some("a b/c\u{2713}"));
+        // the query string of merged params is encoded again
+        assert_eq!(params.merge_qs("name=x&n=1"), "name=a+b%2Fc%E2%9C%93&n=1");
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Post {
+        id: u32,
+        post_id: u32,
+        #[serde(default)]
+        title: String,
+    }
+
+    fn client() -> TestClient {
+        let post = || sync::serv(|req: Post| -> Result<Post> { Ok(req) });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/users/:id/posts/:post_id", post());
+        routes.push(Method::POST, "/users/:id/posts/:post_id", post());
+        TestClient::new(Server::new(routes))
+    }
+
+    #[test]
+    fn path_params_take_precedence() {
+        let mut client = client();
+        let body = serde_json::json!({"id": 99, "title": "hello"});
+        let resp = client.post::<_, Post>("/users/1/posts/2", &body);
+        let expected = Post {
+            id: 1,
+            post_id: 2,
+            title: "hello".to_owned(),
+        };
+        assert_eq!(resp.into_result(), expected);
+
+        let resp = client.get::<_, Post>("/users/1/posts/2?id=99&title=hello", &Empty {});
+        assert_eq!(resp.into_result(), expected);
+    }
+
+    #[test]
+    fn invalid_params_are_bad_requests() {
+        let mut client = client();
+        let body = serde_json::json!({"title": "hello"});
+        client
+            .post::<_, Post>("/users/alice/posts/2", &body)
+            .assert_status(StatusCode::BAD_REQUEST)
+            .assert_reason("badarg");
+        client
+            .get::<_, Post>("/users/alice/posts/2", &Empty {})
+            .assert_status(StatusCode::BAD_REQUEST)
+            .assert_reason("badarg");
+    }
+}
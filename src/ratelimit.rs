@@ -0,0 +1,237 @@
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::*;
+use hyper::{Body, Request, Response};
+
+use context::PeerAddr;
+use error::ErrorKind;
+use middleware::Middleware;
+use reply::ErrorReply;
+
+/// Clients tracked by default, see `RateLimit::max_clients`.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token-bucket rate limiter, applied as `Middleware` to a route with `middleware::layer`, to
+/// a group of routes with `Routes::layer`, or to every request with `Server::with_middleware`.
+/// Each client gets `burst` tokens refilled at `rate`, requests without a token get
+/// `429 Too Many Requests` with `Retry-After` and `rate_limited` reason.
+///
+/// Clients are keyed by IP address by default, see `by_header` and `by`. Requests without a
+/// key share a bucket.
+pub struct RateLimit {
+    /// tokens per second
+    rate: f64,
+    burst: f64,
+    key: Key,
+    max_buckets: usize,
+    buckets: Mutex<Buckets>,
+}
+
+/// custom key of `RateLimit::by`
+type KeyFn = Box<Fn(&Request<Body>) -> Option<String> + Send + Sync>;
+
+enum Key {
+    Peer,
+    Header(HeaderName),
+    Custom(KeyFn),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// sequence number of the last use, key of `Buckets::used`
+    used: u64,
+}
+
+/// Buckets by key, with keys ordered by last use to forget the least recently used client.
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    used: BTreeMap<u64, String>,
+    seq: u64,
+}
+
+impl RateLimit {
+    /// Allow `requests` per `per` for each client, with bursts of up to `requests`.
+    pub fn new(requests: u32, per: Duration) -> Self {
+        let per = per.as_secs() as f64 + f64::from(per.subsec_nanos()) / 1e9;
+        Self {
+            rate: f64::from(requests) / per,
+            burst: f64::from(requests),
+            key: Key::Peer,
+            max_buckets: MAX_BUCKETS,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Allow bursts of up to `burst` requests.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = f64::from(burst);
+        self
+    }
+
+    /// Key clients by value of header `name`, e.g. an API key.
+    pub fn by_header(mut self, name: HeaderName) -> Self {
+        self.key = Key::Header(name);
+        self
+    }
+
+    /// Key clients by `f`.
+    pub fn by<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Key::Custom(Box::new(f));
+        self
+    }
+
+    /// Track at most `max` clients, 10 000 by default. A new client over the limit replaces
+    /// the client seen least recently, which starts over with a full bucket if it comes back.
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.max_buckets = std::cmp::max(max, 1);
+        self
+    }
+
+    fn key(&self, req: &Request<Body>) -> Option<String> {
+        match self.key {
+            Key::Peer => match req.extensions().get::<PeerAddr>()? {
+                PeerAddr::Tcp(addr) => Some(addr.ip().to_string()),
+                #[cfg(feature = "uds")]
+                PeerAddr::Unix(_) => None,
+            },
+            Key::Header(ref name) => {
+                let value = req.headers().get(name)?;
+                value.to_str().ok().map(|value| value.to_owned())
+            }
+            Key::Custom(ref f) => f(req),
+        }
+    }
+
+    /// take a token of `key`, returns time until the next token if there is none
+    fn take(&self, key: String) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("poisoned");
+        let bucket = buckets.get(key, now, self.burst, self.max_buckets);
+        let tokens = bucket.refill(now, self.rate, self.burst);
+        if tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            let secs = (1.0 - tokens) / self.rate;
+            Some(Duration::from_millis((secs * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+impl Buckets {
+    /// bucket of `key`, marked as the most recently used. A new bucket is full, it replaces the
+    /// least recently used bucket if there are `max` already.
+    fn get(&mut self, key: String, now: Instant, burst: f64, max: usize) -> &mut Bucket {
+        self.seq += 1;
+        let seq = self.seq;
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            self.used.remove(&bucket.used);
+            bucket.used = seq;
+        } else {
+            if self.buckets.len() >= max {
+                let oldest = self.used.keys().next().cloned();
+                if let Some(oldest) = oldest.and_then(|seq| self.used.remove(&seq)) {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            let bucket = Bucket {
+                tokens: burst,
+                updated: now,
+                used: seq,
+            };
+            self.buckets.insert(key.clone(), bucket);
+        }
+        self.used.insert(seq, key.clone());
+        self.buckets.get_mut(&key).expect("bucket is inserted")
+    }
+}
+
+impl Bucket {
+    /// add tokens for the time since the last update, returns tokens
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+impl Middleware for RateLimit {
+    fn request(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        let key = self.key(req).unwrap_or_default();
+        let wait = self.take(key)?;
+
+        let mut resp = ErrorReply::new(req).response(ErrorKind::RateLimited);
+        // whole seconds, rounded up
+        let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+        resp.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+        Some(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use {middleware, Empty};
+
+    fn ping(client: &mut TestClient, key: &str) -> TestResponse<String> {
+        let req = Request::get("/ping")
+            .header("x-client", key)
+            .body(Body::empty());
+        client.request(req.unwrap())
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_rejected() {
+        let limit = RateLimit::new(2, Duration::from_secs(3600))
+            .by_header(HeaderName::from_static("x-client"));
+        let pong = sync::serv(|_req: Empty| -> ::error::Result<String> { Ok("pong".into()) });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/ping", middleware::layer(limit, pong));
+        let mut client = TestClient::new(Server::new(routes));
+
+        ping(&mut client, "a").assert_status(StatusCode::OK);
+        ping(&mut client, "a").assert_status(StatusCode::OK);
+        let resp = ping(&mut client, "a");
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS)
+            .assert_reason("rate_limited");
+        let retry_after = resp.headers().get(RETRY_AFTER).unwrap();
+        assert_eq!(retry_after, "1800");
+        // other clients have their own bucket
+        ping(&mut client, "b").assert_status(StatusCode::OK);
+    }
+
+    #[test]
+    fn clients_over_the_limit_replace_the_least_recent() {
+        let limit = RateLimit::new(1, Duration::from_secs(3600)).max_clients(2);
+        assert!(limit.take("a".into()).is_none());
+        assert!(limit.take("b".into()).is_none());
+        assert!(limit.take("a".into()).is_some());
+        // replaces b, which was seen before a
+        assert!(limit.take("c".into()).is_none());
+        assert!(limit.take("a".into()).is_some());
+        assert!(limit.take("b".into()).is_none());
+
+        for i in 0..1000 {
+            limit.take(i.to_string());
+        }
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.used.len(), 2);
+    }
+}
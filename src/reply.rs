@@ -1,6 +1,7 @@
 use super::*;
 
 use async::*;
+use context::RequestId;
 use hyper::header::*;
 use std::convert::From;
 
@@ -8,80 +9,321 @@ use std::convert::From;
 pub trait Reply<T, E>: serde::Serialize + From<Result<T, E>>
 where
     T: serde::Serialize + 'static,
-    E: From<Error> + 'static,
+    E: From<Error> + ServError + 'static,
 {
+    /// `reason` of an error reply
+    fn reason(&self) -> Option<&str> {
+        None
+    }
+
+    /// include request id `id` in an error reply
+    fn with_request_id(self, _id: &str) -> Self {
+        self
+    }
+
     /// write reply body
     fn reply(&self, status: hyper::StatusCode) -> HyperFuture {
-        let encoded = match serde_json::to_vec(&self) {
+        self.reply_as(status, Codec::Json)
+    }
+
+    /// write reply body encoded with `codec`
+    fn reply_as(&self, status: hyper::StatusCode, codec: Codec) -> HyperFuture {
+        Box::new(ok(self.response_as(status, codec)))
+    }
+
+    /// build reply response
+    fn response(&self, status: hyper::StatusCode) -> hyper::Response<Body> {
+        self.response_as(status, Codec::Json)
+    }
+
+    /// build reply response encoded with `codec`
+    fn response_as(&self, status: hyper::StatusCode, codec: Codec) -> hyper::Response<Body> {
+        let encoded = match codec.encode(&self) {
             Ok(encoded) => encoded,
             Err(e) => {
-                return Box::new(ok(resp_serv_err::<Error>(
-                    ErrorKind::EncodeJson(e).into(),
-                    hyper::StatusCode::OK,
-                )));
+                let status = e.status();
+                return resp_serv_err(e, status);
             }
         };
 
         let header_len = HeaderValue::from_str(&encoded.len().to_string())
             .expect("should not b an invalid utf-8");
 
-        Box::new(result(
-            Response::builder()
-                .status(status)
-                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                .header(CACHE_CONTROL, "no-cache, no-store, must-revalidate")
-                .header(CONTENT_TYPE, "application/json")
-                .header(CONTENT_LENGTH, header_len)
-                .body(encoded.into())
-                .or_else(|e| Ok(resp_serv_err(e, hyper::StatusCode::OK))),
-        ))
+        let mut builder = hyper::Response::builder();
+        if let Some(reason) = self.reason() {
+            builder.extension(ErrorReason(reason.to_owned()));
+        }
+        builder
+            .status(status)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+            .header(CONTENT_TYPE, codec.content_type())
+            .header(CONTENT_LENGTH, header_len)
+            .body(encoded.into())
+            .unwrap_or_else(|e| resp_serv_err(e, hyper::StatusCode::OK))
     }
 
     /// `serv_state` build `HyperService` with given function `F` and state `S`.
-    fn serv_state<F, S, Req>(state: S, f: F) -> HyperService
+    fn serv_state<F, S, Req, Resp>(state: S, f: F) -> HyperService
     where
         Self: 'static,
-        F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = T, Error = E>> + 'static,
+        Resp: IntoResponse<Body = T> + 'static,
+        F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
         S: 'static,
         Req: for<'de> serde::Deserialize<'de> + 'static,
     {
-        let f = AsyncServiceFn::new(move |req| f(&state, req));
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(&state, req));
         Box::new(async::AsyncServiceStateW::<_, Self>::new(f))
     }
 
     /// `service` builds `HyperService` with given function `F`.
-    fn serv<F, Req>(f: F) -> HyperService
+    fn serv<F, Req, Resp>(f: F) -> HyperService
     where
         Self: 'static,
-        F: Fn(Req) -> Box<Future<Item = T, Error = E>> + 'static,
+        Resp: IntoResponse<Body = T> + 'static,
+        F: Fn(Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
         Req: for<'de> serde::Deserialize<'de> + 'static,
     {
-        let f = AsyncServiceFn::new(f);
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(req));
         Box::new(async::AsyncServiceStateW::<_, Self>::new(f))
     }
 
     /// `serv_state` builds `HyperService` with given function `F` and state `S`.
-    fn serv_state_sync<F, S, Req>(state: S, f: F) -> HyperService
+    fn serv_state_sync<F, S, Req, Resp>(state: S, f: F) -> HyperService
     where
         Self: 'static,
-        F: for<'a> Fn(&'a S, Req) -> Result<T, E> + 'static,
+        Resp: IntoResponse<Body = T> + 'static,
+        F: for<'a> Fn(&'a S, Req) -> Result<Resp, E> + 'static,
         S: 'static,
         Req: for<'de> serde::Deserialize<'de> + 'static,
     {
-        let f = async::AsyncServiceFn::new(move |req| Box::new(result(f(&state, req))));
+        let f = async::AsyncServiceFn::new(move |_ctx: &RequestContext, req| {
+            Box::new(result(f(&state, req)))
+        });
         Box::new(async::AsyncServiceStateW::<_, Self>::new(f))
     }
 
     /// `serv` build `HyperService` with given function `F`.
-    fn serv_sync<F, Req>(f: F) -> HyperService
+    fn serv_sync<F, Req, Resp>(f: F) -> HyperService
     where
         Self: 'static,
-        F: Fn(Req) -> Result<T, E> + 'static,
+        Resp: IntoResponse<Body = T> + 'static,
+        F: Fn(Req) -> Result<Resp, E> + 'static,
         Req: for<'de> serde::Deserialize<'de> + 'static,
     {
-        let f = async::AsyncServiceFn::new(move |req| Box::new(result(f(req))));
+        let f =
+            async::AsyncServiceFn::new(move |_ctx: &RequestContext, req| Box::new(result(f(req))));
         Box::new(async::AsyncServiceStateW::<_, Self>::new(f))
     }
+
+    /// `serv_ctx` builds `HyperService` with given function `F`, which also receives
+    /// `RequestContext`.
+    fn serv_ctx<F, Req, Resp, Fut>(f: F) -> HyperService
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + 'static,
+        F: for<'a> Fn(&'a RequestContext, Req) -> Fut + 'static,
+        Fut: Future<Item = Resp, Error = E> + 'static,
+        Req: for<'de> serde::Deserialize<'de> + 'static,
+    {
+        let f = AsyncServiceFn::new(f);
+        Box::new(async::AsyncServiceStateW::<_, Self>::new(f))
+    }
+
+    /// `serv_ctx_send` builds `HyperServiceSync` with given function `F`, which also receives
+    /// `RequestContext`.
+    fn serv_ctx_send<F, Req, Resp, Fut>(f: F) -> HyperServiceSync
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + Send + 'static,
+        E: Send,
+        F: for<'a> Fn(&'a RequestContext, Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Item = Resp, Error = E> + Send + 'static,
+        Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let f = AsyncServiceFn::new(f);
+        Box::new(async::AsyncServiceSendW::<_, Self>::new(f))
+    }
+
+    /// `serv_state_send` builds `HyperServiceSync` with given function `F` and state `S`.
+    fn serv_state_send<F, S, Req, Resp>(state: S, f: F) -> HyperServiceSync
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + Send + 'static,
+        E: Send,
+        F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E> + Send>
+            + Send
+            + Sync
+            + 'static,
+        S: Send + Sync + 'static,
+        Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(&state, req));
+        Box::new(async::AsyncServiceSendW::<_, Self>::new(f))
+    }
+
+    /// `serv_send` builds `HyperServiceSync` with given function `F`.
+    fn serv_send<F, Req, Resp>(f: F) -> HyperServiceSync
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + Send + 'static,
+        E: Send,
+        F: Fn(Req) -> Box<Future<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+        Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(req));
+        Box::new(async::AsyncServiceSendW::<_, Self>::new(f))
+    }
+
+    /// `serv_state_sync_send` builds `HyperServiceSync` with given function `F` and state `S`.
+    fn serv_state_sync_send<F, S, Req, Resp>(state: S, f: F) -> HyperServiceSync
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + Send + 'static,
+        E: Send,
+        F: for<'a> Fn(&'a S, Req) -> Result<Resp, E> + Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| result(f(&state, req)));
+        Box::new(async::AsyncServiceSendW::<_, Self>::new(f))
+    }
+
+    /// `serv_sync_send` builds `HyperServiceSync` with given function `F`.
+    fn serv_sync_send<F, Req, Resp>(f: F) -> HyperServiceSync
+    where
+        Self: 'static,
+        Resp: IntoResponse<Body = T> + Send + 'static,
+        E: Send,
+        F: Fn(Req) -> Result<Resp, E> + Send + Sync + 'static,
+        Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| result(f(req)));
+        Box::new(async::AsyncServiceSendW::<_, Self>::new(f))
+    }
+}
+
+/// Handler response with custom status and headers. `body` is still replied in the envelope of
+/// `Reply`, e.g. `ServiceReply::Ok`.
+#[derive(Debug)]
+pub struct Response<T> {
+    status: hyper::StatusCode,
+    headers: HeaderMap,
+    body: T,
+}
+
+impl<T> Response<T> {
+    pub fn new(body: T) -> Self {
+        Self {
+            status: hyper::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body,
+        }
+    }
+
+    /// set status code, e.g. `201 Created`
+    pub fn status(mut self, status: hyper::StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// append header, which replaces default headers of `Reply::response` with the same name.
+    pub fn header<K>(mut self, name: K, value: HeaderValue) -> Self
+    where
+        K: IntoHeaderName,
+    {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &T {
+        &self.body
+    }
+}
+
+/// Value which handlers can return, either plain `Serialize` body or `Response`.
+pub trait IntoResponse {
+    type Body: serde::Serialize;
+
+    fn into_response(self) -> Response<Self::Body>;
+}
+
+impl<T> IntoResponse for T
+where
+    T: serde::Serialize,
+{
+    type Body = T;
+
+    fn into_response(self) -> Response<T> {
+        Response::new(self)
+    }
+}
+
+impl<T> IntoResponse for Response<T>
+where
+    T: serde::Serialize,
+{
+    type Body = T;
+
+    fn into_response(self) -> Response<T> {
+        self
+    }
+}
+
+/// Status and headers of a handler response, applied to the reply.
+pub(crate) struct ResponseParts {
+    status: hyper::StatusCode,
+    headers: HeaderMap,
+}
+
+impl ResponseParts {
+    /// split handler result into parts and result to reply
+    pub(crate) fn split<R, E>(resp: Result<R, E>) -> (Self, Result<R::Body, E>)
+    where
+        R: IntoResponse,
+        E: ServError,
+    {
+        match resp {
+            Ok(resp) => {
+                let Response {
+                    status,
+                    headers,
+                    body,
+                } = resp.into_response();
+                (ResponseParts { status, headers }, Ok(body))
+            }
+            Err(e) => {
+                let parts = ResponseParts {
+                    status: e.status(),
+                    headers: HeaderMap::new(),
+                };
+                (parts, Err(e))
+            }
+        }
+    }
+
+    pub(crate) fn status(&self) -> hyper::StatusCode {
+        self.status
+    }
+
+    /// add handler headers to `resp`, replacing headers with the same name
+    pub(crate) fn apply(self, resp: &mut hyper::Response<Body>) {
+        let headers = resp.headers_mut();
+        let mut name = None;
+        for (key, value) in self.headers {
+            if let Some(key) = key {
+                headers.remove(&key);
+                name = Some(key);
+            }
+            if let Some(ref name) = name {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -95,6 +337,9 @@ pub enum ServiceReply<T: serde::Serialize, E> {
         reason: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         msg: Option<String>,
+        /// see `Server::with_request_id_in_errors`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
         #[serde(skip)]
         _e: E,
     },
@@ -112,6 +357,7 @@ where
         ServiceReply::Err {
             reason,
             msg: Some(msg),
+            request_id: None,
             _e: e,
         }
     }
@@ -122,6 +368,7 @@ where
         ServiceReply::Err {
             reason,
             msg: None,
+            request_id: None,
             _e: e,
         }
     }
@@ -143,8 +390,158 @@ where
     }
 }
 
+/// `ServiceReply` decoded by clients, see `client` and `test`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "status")]
+pub(crate) enum RemoteReply<T> {
+    #[serde(rename = "ok")]
+    Ok { result: T },
+    #[serde(rename = "error")]
+    Err { reason: String, msg: Option<String> },
+}
+
 impl<T, E> Reply<T, E> for ServiceReply<T, E>
 where
     T: serde::Serialize + 'static,
-    E: From<Error> + Debug + std::error::Error + 'static,
-{}
+    E: HandlerError,
+{
+    fn reason(&self) -> Option<&str> {
+        match *self {
+            ServiceReply::Err { ref reason, .. } => Some(reason),
+            ServiceReply::Ok { .. } => None,
+        }
+    }
+
+    fn with_request_id(mut self, id: &str) -> Self {
+        if let ServiceReply::Err {
+            ref mut request_id, ..
+        } = self
+        {
+            *request_id = Some(id.to_owned());
+        }
+        self
+    }
+}
+
+/// Error replies to a request which are not written by its handler, e.g. by middlewares.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ErrorReply {
+    /// codec negotiated by `Accept`
+    codec: Codec,
+    /// request id to include, see `reply_request_id`
+    request_id: Option<String>,
+}
+
+impl ErrorReply {
+    pub(crate) fn new<B>(req: &Request<B>) -> Self {
+        Self {
+            codec: Codec::reply(req.headers()),
+            request_id: reply_request_id(req),
+        }
+    }
+
+    /// error reply of `kind`
+    pub(crate) fn response(self, kind: ErrorKind) -> hyper::Response<Body> {
+        let e = Error::from(kind);
+        let status = e.status();
+        self.status_response(e, status)
+    }
+
+    /// error reply of `e` with `status`
+    pub(crate) fn status_response(
+        self,
+        e: Error,
+        status: hyper::StatusCode,
+    ) -> hyper::Response<Body> {
+        let reply = ServiceReply::<(), Error>::from(e);
+        match self.request_id {
+            Some(id) => reply.with_request_id(&id).response_as(status, self.codec),
+            None => reply.response_as(status, self.codec),
+        }
+    }
+}
+
+/// request id to include in error replies to `req`, see `Server::with_request_id_in_errors`
+pub(crate) fn reply_request_id<B>(req: &Request<B>) -> Option<String> {
+    let id = req.extensions().get::<RequestId>()?;
+    if id.reply {
+        Some(id.id.clone())
+    } else {
+        None
+    }
+}
+
+/// `reason` of an error reply, stored in response extensions for `Metrics`.
+#[derive(Clone, Debug)]
+pub(crate) struct ErrorReason(pub(crate) String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use hyper::Method;
+    use tokio::runtime::current_thread::Runtime;
+
+    use server::{Routes, Server};
+    use sync;
+
+    fn created(name: String) -> Response<String> {
+        let location = HeaderValue::from_str(&format!("/users/{}", name)).unwrap();
+        Response::new(name)
+            .status(hyper::StatusCode::CREATED)
+            .header(LOCATION, location)
+            .header(CACHE_CONTROL, HeaderValue::from_static("max-age=60"))
+            .header("x-tag", HeaderValue::from_static("a"))
+            .header("x-tag", HeaderValue::from_static("b"))
+    }
+
+    /// raw response of `POST path` with body `"bob"` from a server on another thread
+    fn post(path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let create = sync::serv(|name: String| -> Result<_, Error> { Ok(created(name)) });
+            let create_send =
+                sync::serv_send(|name: String| -> Result<_, Error> { Ok(created(name)) });
+            let mut routes = Routes::new();
+            routes.push(Method::POST, "/users", create);
+            routes.push_send(Method::POST, "/send/users", create_send);
+            let mut rt = Runtime::new().unwrap();
+            rt.block_on(Server::new(routes).run_listener(listener))
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let req = format!(
+            "POST {} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json\r\n\
+             content-length: 5\r\nconnection: close\r\n\r\n\"bob\"",
+            path
+        );
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp.to_lowercase()
+    }
+
+    #[test]
+    fn response_parts_reach_the_wire() {
+        for path in &["/users", "/send/users"] {
+            let resp = post(path);
+            assert!(resp.starts_with("http/1.1 201 created\r\n"), "{}", resp);
+            assert!(resp.contains("\r\nlocation: /users/bob\r\n"), "{}", resp);
+            assert!(
+                resp.contains("\r\ncache-control: max-age=60\r\n"),
+                "{}",
+                resp
+            );
+            assert!(!resp.contains("no-store"), "{}", resp);
+            assert!(resp.contains("\r\nx-tag: a\r\nx-tag: b\r\n"), "{}", resp);
+            assert!(
+                resp.ends_with(r#"{"status":"ok","result":"bob"}"#),
+                "{}",
+                resp
+            );
+        }
+    }
+}
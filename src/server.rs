@@ -1,37 +1,86 @@
 use std;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::future::*;
+use futures::Stream;
 use hyper;
+use hyper::header::{HeaderValue, ORIGIN};
 use hyper::server::conn::Http;
+use hyper::service::MakeService;
 use hyper::{Body, Request, Response};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
 use tokio_current_thread as current_thread;
+use tokio_io::{AsyncRead, AsyncWrite};
 use url;
 
+#[cfg(feature = "compression")]
+use compression::{Compress, Compression, Compressor};
+use conn::Conn;
+use context::{Deadline, MatchedRoute, PeerAddr, RequestId, REQUEST_ID};
+use cors::Cors;
 use error::*;
-use resp_serv_err;
+use metrics::{self, Metrics};
+use middleware::{self, Layered, Middleware};
+#[cfg(feature = "openapi")]
+use openapi;
+use path::{PathParams, PathPattern};
+use reply::{ErrorReason, ErrorReply};
+#[cfg(feature = "tls")]
+use tls::{ClientCert, TlsConfig, TlsIncoming, TlsStream};
+use BodyLimit;
+use HyperFuture;
+use HyperFutureSend;
 use HyperService;
-use HyperServiceSend;
+use HyperServiceSync;
 
 enum RoutePath {
     Exact(String),
     Prefix(String),
+    Pattern(PathPattern),
 }
 
 enum RouteService {
     NotSend(RefCell<HyperService>),
-    Send(RefCell<HyperServiceSend>),
+    Send(HyperServiceSync),
 }
 impl From<HyperService> for RouteService {
     fn from(s: HyperService) -> RouteService {
         RouteService::NotSend(RefCell::new(s))
     }
 }
-impl From<HyperServiceSend> for RouteService {
-    fn from(s: HyperServiceSend) -> RouteService {
-        RouteService::Send(RefCell::new(s))
+impl From<HyperServiceSync> for RouteService {
+    fn from(s: HyperServiceSync) -> RouteService {
+        RouteService::Send(s)
+    }
+}
+
+impl RouteService {
+    fn layer(self, middleware: Arc<Middleware>) -> RouteService {
+        match self {
+            RouteService::NotSend(serv) => {
+                let serv: HyperService =
+                    Box::new(Layered::new(vec![middleware], serv.into_inner()));
+                serv.into()
+            }
+            RouteService::Send(serv) => {
+                let serv: HyperServiceSync = Box::new(Layered::new(vec![middleware], serv));
+                serv.into()
+            }
+        }
+    }
+
+    /// `Send` services are cloned for each request, see `ServiceSend`
+    fn call(&self, req: Request<Body>) -> HyperFuture {
+        match *self {
+            RouteService::NotSend(ref serv) => serv.borrow_mut().call(req),
+            RouteService::Send(ref serv) => serv.clone().call(req),
+        }
     }
 }
 
@@ -40,51 +89,68 @@ type FstMap = ::fst::Map;
 #[cfg(not(feature = "fst"))]
 type FstMap = ();
 
-#[derive(Default)]
-pub struct Routes {
-    routes: Vec<(String, RouteService)>,
+/// Settings of a route or the server, route settings take precedence.
+#[derive(Clone, Copy, Default)]
+struct RouteSettings {
+    body_limit: Option<usize>,
+    timeout: Option<Duration>,
+    #[cfg(feature = "compression")]
+    compress: Option<bool>,
+}
+
+struct RouteTable<S> {
+    routes: Vec<(String, S)>,
+    patterns: Vec<(hyper::Method, PathPattern, S)>,
+    /// settings by method and route
+    settings: Vec<(hyper::Method, String, RouteSettings)>,
     #[allow(unused)]
     map: FstMap,
 }
 
-impl Routes {
-    pub fn new() -> Self {
+impl<S> Default for RouteTable<S> {
+    fn default() -> Self {
         Self {
             routes: Vec::new(),
+            patterns: Vec::new(),
+            settings: Vec::new(),
             map: Default::default(),
         }
     }
+}
 
-    fn push_serv<S>(&mut self, method: hyper::Method, path: RoutePath, service: S)
-    where
-        S: Into<RouteService>,
-    {
+impl<S> RouteTable<S> {
+    fn push(&mut self, method: hyper::Method, path: RoutePath, service: S) {
         let key = match path {
             RoutePath::Exact(s) => format!("{}?{}?", method, s),
             RoutePath::Prefix(s) => format!("{}?{}", method, s),
+            RoutePath::Pattern(p) => {
+                self.patterns.push((method, p, service));
+                return;
+            }
         };
-        self.routes.push((key, service.into()));
-    }
-
-    pub fn push(&mut self, method: hyper::Method, path: &str, service: HyperService) {
-        self.push_serv(method, RoutePath::Exact(path.to_owned()), service)
-    }
-
-    pub fn push_prefix(&mut self, method: hyper::Method, prefix: &str, service: HyperService) {
-        self.push_serv(method, RoutePath::Prefix(prefix.to_owned()), service)
+        self.routes.push((key, service));
     }
 
-    pub fn push_send(&mut self, method: hyper::Method, path: &str, service: HyperServiceSend) {
-        self.push_serv(method, RoutePath::Exact(path.to_owned()), service)
-    }
-
-    pub fn push_send_prefix(
-        &mut self,
-        method: hyper::Method,
-        prefix: &str,
-        service: HyperServiceSend,
-    ) {
-        self.push_serv(method, RoutePath::Prefix(prefix.to_owned()), service)
+    /// build a table with same routes, mapping each service with `f`. Returns description of
+    /// the first route which `f` rejects.
+    fn filter_map<T, F>(&self, f: F) -> std::result::Result<RouteTable<T>, String>
+    where
+        F: Fn(&S) -> Option<T>,
+    {
+        let mut table = RouteTable {
+            settings: self.settings.clone(),
+            ..RouteTable::default()
+        };
+        for (key, serv) in &self.routes {
+            let serv = f(serv).ok_or_else(|| key.trim_end_matches('?').replacen('?', " ", 1))?;
+            table.routes.push((key.clone(), serv));
+        }
+        for (method, pattern, serv) in &self.patterns {
+            let serv = f(serv).ok_or_else(|| format!("{} {}", method, pattern))?;
+            table.patterns.push((method.clone(), pattern.clone(), serv));
+        }
+        table.build();
+        Ok(table)
     }
 
     #[cfg(feature = "fst")]
@@ -123,30 +189,668 @@ impl Routes {
     }
 
     #[cfg(feature = "fst")]
-    fn route(&self, method: hyper::Method, path: &str) -> Option<&RouteService> {
+    fn route_key(&self, method: &hyper::Method, path: &str) -> Option<&(String, S)> {
         let s = format!("{}?{}?", method, path);
         let idx = self.longest_match(s.as_bytes())?;
-        self.routes.get(idx).map(|(_key, serv)| serv)
+        self.routes.get(idx)
     }
 
     #[cfg(not(feature = "fst"))]
     fn build(&mut self) {}
 
     #[cfg(not(feature = "fst"))]
-    fn route(&self, method: hyper::Method, path: &str) -> Option<&RouteService> {
+    fn route_key(&self, method: &hyper::Method, path: &str) -> Option<&(String, S)> {
         let s = format!("{}?{}?", method, path);
-        for (ref key, ref route) in &self.routes {
-            if s.starts_with(key) {
-                return Some(route);
+        self.routes.iter().find(|route| s.starts_with(&route.0))
+    }
+
+    /// Exact routes win over patterns, patterns win over prefixes. Returns matched service,
+    /// captured path params and the matched route.
+    fn route(&self, method: &hyper::Method, path: &str) -> Option<(&S, PathParams, String)> {
+        // strip method and exact route marker from the key
+        let route_path = |key: &str| {
+            let path = key.split_once('?').map_or("", |(_, path)| path);
+            path.trim_end_matches('?').to_owned()
+        };
+
+        let exact = format!("{}?{}?", method, path);
+        if let Some((key, serv)) = self.routes.iter().find(|(key, _serv)| *key == exact) {
+            return Some((serv, PathParams::default(), route_path(key)));
+        }
+        for (m, pattern, serv) in &self.patterns {
+            if m != method {
+                continue;
+            }
+            if let Some(params) = pattern.matches(path) {
+                return Some((serv, params, pattern.to_string()));
             }
         }
-        None
+        self.route_key(method, path)
+            .map(|(key, serv)| (serv, PathParams::default(), route_path(key)))
+    }
+
+    /// methods of the routes which match `path`
+    fn methods(&self, path: &str) -> Vec<hyper::Method> {
+        let mut methods: Vec<hyper::Method> = Vec::new();
+        let keys = self.routes.iter().map(|(key, _serv)| key.split('?').next());
+        let registered = keys
+            .filter_map(|m| m.and_then(|m| m.parse().ok()))
+            .chain(self.patterns.iter().map(|(m, _pattern, _serv)| m.clone()));
+        for method in registered {
+            if !methods.contains(&method) && self.route(&method, path).is_some() {
+                methods.push(method);
+            }
+        }
+        methods
+    }
+
+    /// answer CORS preflight `req` with `cors`, `None` if it should be routed as usual
+    fn preflight(&self, cors: &Cors, req: &Request<Body>) -> Option<Response<Body>> {
+        let path = req.uri().path();
+        if !Cors::is_preflight(req) || self.route(&hyper::Method::OPTIONS, path).is_some() {
+            return None;
+        }
+        let methods = self.methods(path);
+        if methods.is_empty() {
+            return None;
+        }
+        info!("preflight: {}", req.uri());
+        Some(cors.preflight(req, methods))
+    }
+
+    /// build a table with same routes, mapping each service with `f`
+    fn map<F>(self, mut f: F) -> Self
+    where
+        F: FnMut(S) -> S,
+    {
+        let mut table = RouteTable {
+            settings: self.settings,
+            ..RouteTable::default()
+        };
+        for (key, serv) in self.routes {
+            table.routes.push((key, f(serv)));
+        }
+        for (method, pattern, serv) in self.patterns {
+            table.patterns.push((method, pattern, f(serv)));
+        }
+        table
+    }
+
+    /// add routes of `other`
+    fn extend(&mut self, other: RouteTable<S>) {
+        self.routes.extend(other.routes);
+        self.patterns.extend(other.patterns);
+        self.settings.extend(other.settings);
+    }
+
+    /// settings of the route registered at `path`
+    fn settings_mut(&mut self, method: hyper::Method, path: &str) -> &mut RouteSettings {
+        let route = if PathPattern::is_pattern(path) {
+            PathPattern::parse(path).to_string()
+        } else {
+            path.to_owned()
+        };
+        let idx = match self
+            .settings
+            .iter()
+            .position(|(m, r, _settings)| *m == method && *r == route)
+        {
+            Some(idx) => idx,
+            None => {
+                self.settings
+                    .push((method, route, RouteSettings::default()));
+                self.settings.len() - 1
+            }
+        };
+        &mut self.settings[idx].2
+    }
+
+    fn settings(&self, method: &hyper::Method, route: &str) -> RouteSettings {
+        self.settings
+            .iter()
+            .find(|(m, r, _settings)| m == method && r == route)
+            .map(|(_m, _r, settings)| *settings)
+            .unwrap_or_default()
+    }
+
+    /// find a route for `req`, matched route, path params, body size limit and deadline (the
+    /// route's, or `server` settings) are stored in request extensions.
+    fn route_req(&self, req: &mut Request<Body>, server: RouteSettings) -> Option<&S> {
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        match req.extensions().get::<RequestId>() {
+            Some(id) => info!("req: {} {} ({})", method, uri, id.id),
+            None => info!("req: {} {}", method, uri),
+        }
+
+        let (serv, params, route) = self.route(&method, uri.path())?;
+        if !params.is_empty() {
+            req.extensions_mut().insert(params);
+        }
+        let settings = self.settings(&method, &route);
+        if let Some(limit) = settings.body_limit.or(server.body_limit) {
+            req.extensions_mut().insert(BodyLimit(limit));
+        }
+        if let Some(timeout) = settings.timeout.or(server.timeout) {
+            req.extensions_mut()
+                .insert(Deadline(Instant::now() + timeout));
+        }
+        #[cfg(feature = "compression")]
+        {
+            if let Some(compress) = settings.compress.or(server.compress) {
+                req.extensions_mut().insert(Compress(compress));
+            }
+        }
+        req.extensions_mut().insert(MatchedRoute(route));
+        Some(serv)
+    }
+}
+
+#[derive(Default)]
+pub struct Routes {
+    table: RouteTable<RouteService>,
+    #[cfg(feature = "openapi")]
+    docs: openapi::Docs,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push_serv<S>(&mut self, method: hyper::Method, path: RoutePath, service: S)
+    where
+        S: Into<RouteService>,
+    {
+        self.table.push(method, path, service.into())
+    }
+
+    fn exact_or_pattern(path: &str) -> RoutePath {
+        if PathPattern::is_pattern(path) {
+            RoutePath::Pattern(PathPattern::parse(path))
+        } else {
+            RoutePath::Exact(path.to_owned())
+        }
+    }
+
+    /// Register `service` at `path`. Segments starting with `:` (e.g. `/users/:id`) capture
+    /// percent-decoded values which are merged into the handler's request. A captured value
+    /// replaces a body or query field of the same name, e.g. `POST /users/1` with
+    /// `{"id":99}` reads `id` 1. Values which do not parse as the field type get
+    /// `400 Bad Request`. Patterns match whole paths, `/users/:id` does not match `/users/1/`.
+    pub fn push(&mut self, method: hyper::Method, path: &str, service: HyperService) {
+        self.push_serv(method, Self::exact_or_pattern(path), service)
+    }
+
+    pub fn push_prefix(&mut self, method: hyper::Method, prefix: &str, service: HyperService) {
+        self.push_serv(method, RoutePath::Prefix(prefix.to_owned()), service)
+    }
+
+    /// Route to `service` which may be served from multiple threads, see `Server::run_threaded`.
+    /// Each request is served by a clone of `service`.
+    pub fn push_send(&mut self, method: hyper::Method, path: &str, service: HyperServiceSync) {
+        self.push_serv(method, Self::exact_or_pattern(path), service)
+    }
+
+    pub fn push_send_prefix(
+        &mut self,
+        method: hyper::Method,
+        prefix: &str,
+        service: HyperServiceSync,
+    ) {
+        self.push_serv(method, RoutePath::Prefix(prefix.to_owned()), service)
+    }
+
+    /// Limit request body size of the route registered with `method` at `path`, instead of the
+    /// limit of `Server`. Larger requests get `413 Payload Too Large`.
+    pub fn set_body_limit(&mut self, method: hyper::Method, path: &str, limit: usize) {
+        self.table.settings_mut(method, path).body_limit = Some(limit);
+    }
+
+    /// Bound time to reply of the route registered with `method` at `path`, instead of the
+    /// timeout of `Server`. See `Server::with_timeout`.
+    pub fn set_timeout(&mut self, method: hyper::Method, path: &str, timeout: Duration) {
+        self.table.settings_mut(method, path).timeout = Some(timeout);
+    }
+
+    /// Enable or disable compression of replies of the route registered with `method` at
+    /// `path`, instead of the setting of `Server`. Routes enabled without
+    /// `Server::with_compression` use the default `Compression`.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, method: hyper::Method, path: &str, enabled: bool) {
+        self.table.settings_mut(method, path).compress = Some(enabled);
+    }
+
+    /// Apply `middleware` to every route registered so far. Routes pushed later are not
+    /// affected, so a group of routes can be built as separate `Routes` and added with `merge`.
+    /// The middleware runs outside middlewares applied before it.
+    pub fn layer<M>(&mut self, middleware: M)
+    where
+        M: Middleware + 'static,
+    {
+        let middleware: Arc<Middleware> = Arc::new(middleware);
+        let table = std::mem::take(&mut self.table);
+        self.table = table.map(|serv| serv.layer(middleware.clone()));
+    }
+
+    /// Add routes of `other`, e.g. a group of routes with its own middlewares.
+    pub fn merge(&mut self, other: Routes) {
+        self.table.extend(other.table);
+        #[cfg(feature = "openapi")]
+        self.docs.extend(other.docs);
+    }
+
+    /// Describe the route registered with `method` at `path` in the OpenAPI document.
+    #[cfg(feature = "openapi")]
+    pub fn document(&mut self, method: hyper::Method, path: &str, op: openapi::Operation) {
+        self.docs.push(method, path, op)
+    }
+
+    /// OpenAPI 3 document of routes described with `document`.
+    #[cfg(feature = "openapi")]
+    pub fn openapi(&self, title: &str, version: &str) -> serde_json::Value {
+        self.docs.document(title, version)
+    }
+
+    /// Serve the OpenAPI document at `GET path`. The document is built by `Server::new`, so it
+    /// includes routes described after this call.
+    #[cfg(feature = "openapi")]
+    pub fn serve_openapi(&mut self, path: &str, title: &str, version: &str) {
+        self.docs.serve(path, title, version)
+    }
+
+    fn build(&mut self) {
+        #[cfg(feature = "openapi")]
+        {
+            if let Some((path, serv)) = self.docs.service() {
+                self.push_send(hyper::Method::GET, &path, serv);
+            }
+        }
+        self.table.build()
+    }
+
+    /// routes for `Server::run_threaded`, fails if any route is not `Send`.
+    fn send_table(&self) -> Result<RouteTable<HyperServiceSync>> {
+        self.table
+            .filter_map(|serv| match serv {
+                RouteService::NotSend(_) => None,
+                RouteService::Send(serv) => Some(serv.clone()),
+            })
+            .map_err(|route| format!("route is not Send: {}", route).into())
+    }
+}
+
+/// drop `f` and reply `timeout` error if it does not resolve until `deadline`
+fn with_deadline<F>(
+    deadline: Instant,
+    to: ErrorReply,
+    f: F,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+where
+    F: Future<Item = Response<Body>, Error = hyper::Error>,
+{
+    let timeout = Delay::new(deadline).then(move |res| match res {
+        Ok(()) => {
+            info!("timeout");
+            Either::A(ok(to.response(ErrorKind::Timeout)))
+        }
+        // e.g. no timer on the executor, the request runs without deadline
+        Err(e) => {
+            warn!("failed to start request timer: {}", e);
+            Either::B(empty())
+        }
+    });
+    f.select(timeout)
+        .map(|(resp, _next)| resp)
+        .map_err(|(e, _next)| e)
+}
+
+/// start timing routed `req`, requests which match no route are recorded with an empty route
+fn start_timer(metrics: &Metrics, req: &Request<Body>) -> metrics::Timer {
+    let route = match req.extensions().get::<MatchedRoute>() {
+        Some(route) => route.0.as_str(),
+        None => "",
+    };
+    metrics.start(req.method(), route)
+}
+
+/// reply request `id` in response header of `f`
+fn echo_request_id<F>(
+    id: HeaderValue,
+    f: F,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+where
+    F: Future<Item = Response<Body>, Error = hyper::Error>,
+{
+    f.map(move |mut resp| {
+        if let Some(reason) = resp.extensions().get::<ErrorReason>() {
+            let id = id.to_str().unwrap_or_default();
+            info!("reply: {} {} ({})", resp.status(), reason.0, id);
+        }
+        resp.headers_mut().insert(REQUEST_ID, id);
+        resp
+    })
+}
+
+/// apply `cors` to response of `f` for request `origin`
+fn with_cors<F>(
+    cors: Arc<Cors>,
+    origin: Option<hyper::header::HeaderValue>,
+    f: F,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+where
+    F: Future<Item = Response<Body>, Error = hyper::Error>,
+{
+    f.map(move |mut resp| {
+        cors.apply(origin.as_ref(), &mut resp);
+        resp
+    })
+}
+
+type ShutdownSignal = Shared<Box<Future<Item = (), Error = ()> + Send>>;
+
+/// Graceful shutdown state, shared by server futures and in-flight requests.
+#[derive(Clone)]
+struct Shutdown {
+    signal: ShutdownSignal,
+    /// resolves `grace` after `signal`
+    deadline: ShutdownSignal,
+}
+
+impl Shutdown {
+    fn new<F>(signal: F, grace: Duration) -> Self
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let signal: Box<Future<Item = (), Error = ()> + Send> = Box::new(signal);
+        let signal = signal.shared();
+        let deadline: Box<Future<Item = (), Error = ()> + Send> = Box::new(
+            signal
+                .clone()
+                .then(move |_| Delay::new(Instant::now() + grace))
+                .then(|res| match res {
+                    Ok(()) => Either::A(ok(())),
+                    // in-flight requests are not cut off, the server waits for them instead
+                    Err(e) => {
+                        warn!("failed to start shutdown timer: {}", e);
+                        Either::B(empty())
+                    }
+                }),
+        );
+        Self {
+            signal,
+            deadline: deadline.shared(),
+        }
+    }
+
+    fn signal(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.signal.clone().then(|_| Ok(()))
+    }
+
+    fn deadline(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.deadline.clone().then(|_| Ok(()))
+    }
+
+    /// bound in-flight request `f` by the shutdown deadline
+    fn guard<F>(
+        &self,
+        to: ErrorReply,
+        f: F,
+    ) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+    where
+        F: Future<Item = Response<Body>, Error = hyper::Error>,
+    {
+        f.select2(self.deadline()).then(move |res| match res {
+            Ok(Either::A((resp, _deadline))) => Ok(resp),
+            Err(Either::A((e, _deadline))) => Err(e),
+            Ok(Either::B(_)) | Err(Either::B(_)) => Ok(to.response(ErrorKind::ShuttingDown)),
+        })
+    }
+
+    /// resolve when draining server `f` finishes, or on shutdown deadline
+    fn until<F>(&self, f: F) -> impl Future<Item = (), Error = Error>
+    where
+        F: Future<Item = (), Error = hyper::Error>,
+    {
+        f.map_err(Error::from)
+            .select(self.deadline().map_err(|_| unreachable!()))
+            .map(|_| ())
+            .map_err(|(e, _next)| e)
+    }
+}
+
+/// HTTP version served by `Server` exclusively, see `ServerConfig`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    Http1,
+    Http2,
+}
+
+/// Connection settings of `Server`, applied to every listener, see `Server::with_config`.
+/// Unset values are hyper defaults.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerConfig {
+    /// `None` serves HTTP/1, and HTTP/2 on ALPN `h2` or prior knowledge
+    only: Option<Protocol>,
+    http1_keep_alive: Option<bool>,
+    http2_max_concurrent_streams: Option<u32>,
+    http2_initial_stream_window_size: Option<u32>,
+    http2_initial_connection_window_size: Option<u32>,
+    header_read_timeout: Option<Duration>,
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve HTTP/1 only.
+    pub fn http1_only(mut self) -> Self {
+        self.only = Some(Protocol::Http1);
+        self
+    }
+
+    /// Serve HTTP/2 only. Clients of `http` and `http+unix` urls should connect with prior
+    /// knowledge (h2c), clients of `https` urls negotiate `h2` with ALPN.
+    pub fn http2_only(mut self) -> Self {
+        self.only = Some(Protocol::Http2);
+        self
+    }
+
+    /// Keep HTTP/1 connections open between requests, enabled by default.
+    pub fn http1_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.http1_keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Limit concurrent streams of HTTP/2 connections.
+    pub fn http2_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.http2_max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Initial flow control window of HTTP/2 streams, in bytes.
+    pub fn http2_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_stream_window_size = Some(size);
+        self
+    }
+
+    /// Initial flow control window of HTTP/2 connections, in bytes.
+    pub fn http2_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Close connections which do not send the head of the first request within `timeout`
+    /// after they are accepted, or after the TLS handshake.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = Some(timeout);
+        self
+    }
+
+    fn http(&self) -> Http {
+        let mut http = Http::new();
+        match self.only {
+            Some(Protocol::Http1) => {
+                http.http1_only(true);
+            }
+            Some(Protocol::Http2) => {
+                http.http2_only(true);
+            }
+            None => {}
+        }
+        if let Some(keep_alive) = self.http1_keep_alive {
+            http.keep_alive(keep_alive);
+        }
+        http.http2_max_concurrent_streams(self.http2_max_concurrent_streams)
+            .http2_initial_stream_window_size(self.http2_initial_stream_window_size)
+            .http2_initial_connection_window_size(self.http2_initial_connection_window_size);
+        http
+    }
+
+    /// ALPN protocols of `https` listeners
+    #[cfg(feature = "tls")]
+    fn alpn(&self) -> Vec<Vec<u8>> {
+        let protocols: &[&[u8]] = match self.only {
+            Some(Protocol::Http1) => &[b"http/1.1"],
+            Some(Protocol::Http2) => &[b"h2"],
+            None => &[b"h2", b"http/1.1"],
+        };
+        protocols.iter().map(|protocol| protocol.to_vec()).collect()
+    }
+
+    /// wrap connections of `incoming` to apply settings which hyper does not know
+    fn incoming<I>(&self, incoming: I) -> impl Stream<Item = Conn<I::Item>, Error = I::Error>
+    where
+        I: Stream,
+    {
+        let header_read_timeout = self.header_read_timeout;
+        incoming.map(move |io| Conn::new(io, header_read_timeout))
+    }
+}
+
+/// Settings and connection state of `Server` and `ServerSend`, which differ only in how route
+/// services are shared, see `Pipeline::call`.
+#[derive(Default, Clone)]
+struct Pipeline {
+    shutdown: Option<Shutdown>,
+    cors: Option<Arc<Cors>>,
+    settings: RouteSettings,
+    middlewares: Vec<Arc<Middleware>>,
+    metrics: Option<Metrics>,
+    request_id_in_errors: bool,
+    config: ServerConfig,
+    #[cfg(feature = "compression")]
+    compression: Arc<Compression>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    /// client of the connection being served
+    peer: Option<PeerAddr>,
+    /// set when a request of the connection is received, see `ServerConfig::header_read_timeout`
+    head_received: Option<Arc<AtomicBool>>,
+    /// verified client certificate of the connection being served
+    #[cfg(feature = "tls")]
+    client_cert: Option<ClientCert>,
+}
+
+impl Pipeline {
+    /// state of connection `conn`
+    fn connected<IO>(&mut self, conn: &Conn<IO>)
+    where
+        IO: Peer,
+    {
+        self.peer = conn.get_ref().peer();
+        self.head_received = conn.head_received();
+        #[cfg(feature = "tls")]
+        {
+            self.client_cert = conn.get_ref().client_cert();
+        }
+    }
+
+    /// route `req` in `routes` and reply it with `call` of the route service, with CORS,
+    /// middlewares, deadline, compression, shutdown, metrics and request id around it
+    fn call<S, F, R>(
+        &self,
+        routes: &RouteTable<S>,
+        mut req: Request<Body>,
+        call: F,
+    ) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+    where
+        F: FnOnce(&S, Request<Body>) -> R,
+        R: Future<Item = Response<Body>, Error = hyper::Error>,
+    {
+        if let Some(ref received) = self.head_received {
+            received.store(true, Ordering::Relaxed);
+        }
+        if let Some(ref peer) = self.peer {
+            req.extensions_mut().insert(peer.clone());
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref cert) = self.client_cert {
+                req.extensions_mut().insert(cert.clone());
+            }
+        }
+        if let Some(ref cors) = self.cors {
+            if let Some(resp) = routes.preflight(cors, &req) {
+                return Either::A(ok(resp));
+            }
+        }
+        let origin = req.headers().get(ORIGIN).cloned();
+        let id = RequestId::new(&req, self.request_id_in_errors);
+        let id_value = HeaderValue::from_str(&id.id).ok();
+        req.extensions_mut().insert(id);
+        let serv = routes.route_req(&mut req, self.settings);
+        let timer = self
+            .metrics
+            .as_ref()
+            .map(|metrics| start_timer(metrics, &req));
+        let to = ErrorReply::new(&req);
+        let deadline = req
+            .extensions()
+            .get::<Deadline>()
+            .map(|deadline| deadline.0);
+        #[cfg(feature = "compression")]
+        let compressor = Compressor::new(&self.compression, &req);
+        let (chain, resp) = middleware::enter(&self.middlewares, &mut req);
+        let f = match (resp, serv) {
+            (Some(resp), _) => Either::A(ok(resp)),
+            (None, Some(serv)) => Either::B(call(serv, req)),
+            (None, None) => Either::A(ok(to.clone().response(ErrorKind::InvalidEndpoint))),
+        };
+        let f = optional(deadline, f, |deadline, f| {
+            with_deadline(deadline, to.clone(), f)
+        });
+        let f = chain.leave(f);
+        #[cfg(feature = "compression")]
+        let f = optional(compressor, f, |compressor, f| compressor.compress(f));
+        let f = optional(self.shutdown.as_ref(), f, |shutdown, f| {
+            shutdown.guard(to, f)
+        });
+        let f = optional(timer, f, |timer, f| timer.observe(f));
+        let f = optional(id_value, f, echo_request_id);
+        let f = optional(self.cors.clone(), f, |cors, f| with_cors(cors, origin, f));
+        Either::B(f)
+    }
+}
+
+/// `f` wrapped by `wrap` if `value` is set
+fn optional<T, F, G, W>(value: Option<T>, f: F, wrap: W) -> Either<G, F>
+where
+    F: Future,
+    G: Future<Item = F::Item, Error = F::Error>,
+    W: FnOnce(T, F) -> G,
+{
+    match value {
+        Some(value) => Either::A(wrap(value, f)),
+        None => Either::B(f),
     }
 }
 
 #[derive(Default, Clone)]
 pub struct Server {
     routes: Rc<Routes>,
+    pipeline: Pipeline,
 }
 
 impl Server {
@@ -154,62 +858,383 @@ impl Server {
         routes.build();
         Self {
             routes: Rc::new(routes),
+            pipeline: Pipeline::default(),
         }
     }
 
-    #[cfg(feature = "uds")]
+    /// Stop accepting connections when `signal` resolves (e.g. `futures::sync::oneshot`
+    /// receiver). In-flight requests are given `grace` to finish, requests still running after
+    /// that get `503 Service Unavailable`, and the future returned from `run` resolves.
+    pub fn with_graceful_shutdown<F>(mut self, signal: F, grace: Duration) -> Self
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        self.pipeline.shutdown = Some(Shutdown::new(signal, grace));
+        self
+    }
+
+    /// Reply CORS headers by `cors` instead of allowing any origin, and answer preflight
+    /// requests for every registered route.
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.pipeline.cors = Some(Arc::new(cors));
+        self
+    }
+
+    /// Apply `middleware` to every request, after routing and outside middlewares of routes.
+    /// Requests which match no route are passed to middlewares as well. The middleware runs
+    /// outside middlewares applied before it, as with `Routes::layer`.
+    pub fn with_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware + 'static,
+    {
+        self.pipeline.middlewares.insert(0, Arc::new(middleware));
+        self
+    }
+
+    /// Limit request body size of routes without their own limit, `DEFAULT_BODY_LIMIT` by
+    /// default. See `Routes::set_body_limit`.
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.pipeline.settings.body_limit = Some(limit);
+        self
+    }
+
+    /// Bound time to reply of routes without their own timeout, see `Routes::set_timeout`.
+    /// When the deadline passes, the handler is dropped and `504 Gateway Timeout` is replied
+    /// with `timeout` reason. Streamed bodies are not bounded once the response head is sent.
+    /// Handlers read the remaining time with `RequestContext::remaining`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.pipeline.settings.timeout = Some(timeout);
+        self
+    }
+
+    /// Record per-route metrics of every request to `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.pipeline.metrics = Some(metrics);
+        self
+    }
+
+    /// Include the request id in error replies, e.g. `{"status":"error","request_id":...}`.
+    /// The id is always replied in `X-Request-Id` header.
+    pub fn with_request_id_in_errors(mut self) -> Self {
+        self.pipeline.request_id_in_errors = true;
+        self
+    }
+
+    /// Compress replies of every route by `compression`, see `Routes::set_compression` to
+    /// disable it for a route. Replies get `Vary: Accept-Encoding`.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.pipeline.compression = Arc::new(compression);
+        self.pipeline.settings.compress = Some(true);
+        self
+    }
+
+    /// Apply connection settings of `config` to every listener.
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.pipeline.config = config;
+        self
+    }
+
+    /// Serve `https://` urls with `tls`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.pipeline.tls = Some(tls);
+        self
+    }
+
     pub fn run_uds(self, url: url::Url) -> Box<Future<Item = (), Error = Error>> {
+        Listen::run_uds(self, url)
+    }
+
+    pub fn run_tcp(self, addr: std::net::SocketAddr) -> Box<Future<Item = (), Error = Error>> {
+        Listen::run_tcp(self, addr)
+    }
+
+    /// Serve connections of `listener` which is already bound, e.g. to port 0.
+    pub fn run_listener(
+        self,
+        listener: std::net::TcpListener,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        Listen::run_listener(self, listener)
+    }
+
+    pub fn run_tls(self, addr: std::net::SocketAddr) -> Box<Future<Item = (), Error = Error>> {
+        Listen::run_tls(self, addr)
+    }
+
+    pub fn run(self, url: url::Url) -> Box<Future<Item = (), Error = Error>> {
+        Listen::run(self, url)
+    }
+
+    /// Runs server on a tokio thread pool, e.g. with `tokio::run`. All routes should be
+    /// registered with `Routes::push_send` or `Routes::push_send_prefix`.
+    pub fn run_threaded(self, url: url::Url) -> Box<Future<Item = (), Error = Error> + Send> {
+        match ServerSend::new(&self) {
+            Ok(server) => Listen::run(server, url),
+            Err(e) => Box::new(err(e)),
+        }
+    }
+}
+
+/// Connection which knows its client address.
+trait Peer {
+    fn peer(&self) -> Option<PeerAddr>;
+
+    /// verified TLS client certificate
+    #[cfg(feature = "tls")]
+    fn client_cert(&self) -> Option<ClientCert> {
+        None
+    }
+}
+
+impl Peer for TcpStream {
+    fn peer(&self) -> Option<PeerAddr> {
+        self.peer_addr().ok().map(PeerAddr::Tcp)
+    }
+}
+
+#[cfg(feature = "uds")]
+impl Peer for ::tokio_uds::UnixStream {
+    fn peer(&self) -> Option<PeerAddr> {
+        self.peer_addr().ok().map(PeerAddr::Unix)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Peer for TlsStream<TcpStream> {
+    fn peer(&self) -> Option<PeerAddr> {
+        self.get_ref().peer()
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        TlsStream::client_cert(self)
+    }
+}
+
+/// Unix socket listener which removes the socket file when the server stops.
+#[cfg(feature = "uds")]
+struct UdsIncoming {
+    incoming: ::tokio_uds::Incoming,
+    path: String,
+}
+
+#[cfg(feature = "uds")]
+impl Stream for UdsIncoming {
+    type Item = ::tokio_uds::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+        self.incoming.poll()
+    }
+}
+
+#[cfg(feature = "uds")]
+impl Drop for UdsIncoming {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// `Server` or `ServerSend`, which serve listeners until shutdown. `ServerSend` runs on a
+/// thread pool, so its `Run` is `Send`.
+trait Listen: Sized {
+    type Run;
+
+    /// settings of `https://` listeners
+    #[cfg(feature = "tls")]
+    fn pipeline(&self) -> &Pipeline;
+
+    fn pipeline_mut(&mut self) -> &mut Pipeline;
+
+    /// serve connections of `incoming` until shutdown
+    fn serve<I>(self, incoming: I) -> Self::Run
+    where
+        I: Stream<Error = std::io::Error> + Send + 'static,
+        I::Item: AsyncRead + AsyncWrite + Peer + Send + 'static;
+
+    /// listener which fails with `e`, e.g. https without `TlsConfig`
+    fn fail(e: Error) -> Self::Run;
+
+    #[cfg(feature = "uds")]
+    fn run_uds(self, url: url::Url) -> Self::Run {
         use tokio_uds;
 
-        let path = url.path();
-        if let Err(_) = std::fs::remove_file(path) {
-            //ignore error?
+        let path = url.path().to_owned();
+        // stale socket file, ignore error
+        let _ = std::fs::remove_file(&path);
+
+        let listener = match tokio_uds::UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => return Self::fail(e.into()),
+        };
+        self.serve(UdsIncoming {
+            incoming: listener.incoming(),
+            path,
+        })
+    }
+
+    #[cfg(not(feature = "uds"))]
+    fn run_uds(self, url: url::Url) -> Self::Run {
+        Self::fail(format!("uds not supported: {}", url).into())
+    }
+
+    fn run_tcp(self, addr: std::net::SocketAddr) -> Self::Run {
+        match TcpListener::bind(&addr) {
+            Ok(listener) => self.serve(listener.incoming()),
+            Err(e) => Self::fail(e.into()),
         }
+    }
 
-        let listener = tokio_uds::UnixListener::bind(path).unwrap();
-        let exec = current_thread::TaskExecutor::current();
-        let f = hyper::server::Builder::new(listener.incoming(), Http::new())
-            .executor(exec)
-            .serve(self)
-            .map_err(Error::from);
-        Box::new(f)
+    fn run_listener(self, listener: std::net::TcpListener) -> Self::Run {
+        match TcpListener::from_std(listener, &Handle::default()) {
+            Ok(listener) => self.serve(listener.incoming()),
+            Err(e) => Self::fail(e.into()),
+        }
     }
 
-    pub fn run_tcp(self, addr: std::net::SocketAddr) -> Box<Future<Item = (), Error = Error>> {
-        let listener = TcpListener::bind(&addr).unwrap();
+    #[cfg(feature = "tls")]
+    fn run_tls(self, addr: std::net::SocketAddr) -> Self::Run {
+        let config = {
+            let pipeline = self.pipeline();
+            match pipeline.tls {
+                Some(ref tls) => tls.server_config(pipeline.config.alpn()),
+                None => return Self::fail("https requires Server::with_tls".into()),
+            }
+        };
+        match TcpListener::bind(&addr) {
+            Ok(listener) => self.serve(TlsIncoming::new(listener.incoming(), config)),
+            Err(e) => Self::fail(e.into()),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn run_tls(self, addr: std::net::SocketAddr) -> Self::Run {
+        Self::fail(format!("tls not supported: {}", addr).into())
+    }
+
+    fn run(self, url: url::Url) -> Self::Run {
+        match tcp_addr(&url) {
+            Ok(Some(addr)) if url.scheme() == "https" => self.run_tls(addr),
+            Ok(Some(addr)) => self.run_tcp(addr),
+            Ok(None) => self.run_uds(url),
+            Err(e) => Self::fail(e),
+        }
+    }
+}
+
+impl Listen for Server {
+    type Run = Box<Future<Item = (), Error = Error>>;
+
+    #[cfg(feature = "tls")]
+    fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    fn pipeline_mut(&mut self) -> &mut Pipeline {
+        &mut self.pipeline
+    }
+
+    fn serve<I>(self, incoming: I) -> Self::Run
+    where
+        I: Stream<Error = std::io::Error> + Send + 'static,
+        I::Item: AsyncRead + AsyncWrite + Peer + Send + 'static,
+    {
         let exec = current_thread::TaskExecutor::current();
-        let f = hyper::server::Builder::new(listener.incoming(), Http::new())
+        let shutdown = self.pipeline.shutdown.clone();
+        let config = self.pipeline.config;
+        let server = hyper::server::Builder::new(config.incoming(incoming), config.http())
             .executor(exec)
-            .serve(self)
-            .map_err(Error::from);
-        Box::new(f)
+            .serve(WithPeer(self));
+        match shutdown {
+            Some(shutdown) => {
+                Box::new(shutdown.until(server.with_graceful_shutdown(shutdown.signal())))
+            }
+            None => Box::new(server.map_err(Error::from)),
+        }
     }
 
-    #[cfg(not(feature = "uds"))]
-    pub fn run_uds(self, _url: url::Url) -> Box<Future<Item = (), Error = Error>> {
-        panic!("uds not supported: {:?}", _url);
+    fn fail(e: Error) -> Self::Run {
+        Box::new(err(e))
     }
+}
 
-    pub fn run(self, url: url::Url) -> Box<Future<Item = (), Error = Error>> {
-        let is_unix = match url.scheme() {
-            "http" => false,
-            "http+unix" => true,
-            schema => {
-                panic!("unexpected schema: {}", schema);
+impl Listen for ServerSend {
+    type Run = Box<Future<Item = (), Error = Error> + Send>;
+
+    #[cfg(feature = "tls")]
+    fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    fn pipeline_mut(&mut self) -> &mut Pipeline {
+        &mut self.pipeline
+    }
+
+    fn serve<I>(self, incoming: I) -> Self::Run
+    where
+        I: Stream<Error = std::io::Error> + Send + 'static,
+        I::Item: AsyncRead + AsyncWrite + Peer + Send + 'static,
+    {
+        let shutdown = self.pipeline.shutdown.clone();
+        let config = self.pipeline.config;
+        let server = hyper::server::Builder::new(config.incoming(incoming), config.http())
+            .serve(WithPeer(self));
+        match shutdown {
+            Some(shutdown) => {
+                Box::new(shutdown.until(server.with_graceful_shutdown(shutdown.signal())))
             }
-        };
+            None => Box::new(server.map_err(Error::from)),
+        }
+    }
 
-        if is_unix {
-            self.run_uds(url)
-        } else {
-            //TODO: with_deault_port
-            let addr_str = format!(
-                "{}:{}",
-                url.host().expect("failed to get host"),
-                url.port().expect("failed to get port")
-            );
-            let addr = addr_str.parse().expect("failed to parse addr");
-            self.run_tcp(addr)
+    fn fail(e: Error) -> Self::Run {
+        Box::new(err(e))
+    }
+}
+
+/// `MakeService` which serves each connection with a clone of the server which knows the peer
+struct WithPeer<S>(S);
+
+impl<'a, IO, S> MakeService<&'a Conn<IO>> for WithPeer<S>
+where
+    IO: Peer,
+    S: Listen
+        + Clone
+        + hyper::service::Service<ReqBody = Body, ResBody = Body, Error = hyper::Error>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = S;
+    type Future = FutureResult<Self::Service, Self::MakeError>;
+    type MakeError = hyper::Error;
+
+    fn make_service(&mut self, conn: &'a Conn<IO>) -> Self::Future {
+        let mut server = self.0.clone();
+        server.pipeline_mut().connected(conn);
+        ok(server)
+    }
+}
+
+/// returns `None` for unix domain socket
+fn tcp_addr(url: &url::Url) -> Result<Option<std::net::SocketAddr>> {
+    let is_unix = match url.scheme() {
+        "http" | "https" => false,
+        "http+unix" => true,
+        schema => bail!("unexpected schema: {}", schema),
+    };
+
+    if is_unix {
+        Ok(None)
+    } else {
+        //TODO: with_deault_port
+        let (host, port) = match (url.host(), url.port()) {
+            (Some(host), Some(port)) => (host, port),
+            _ => bail!("host and port expected: {}", url),
+        };
+        match format!("{}:{}", host, port).parse() {
+            Ok(addr) => Ok(Some(addr)),
+            Err(e) => bail!("invalid address {}: {}", url, e),
         }
     }
 }
@@ -221,20 +1246,10 @@ impl hyper::service::Service for Server {
     type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error>>;
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let method = req.method().clone();
-        let uri = req.uri().clone();
-        info!("req: {} {}", method, uri);
-
-        let path = uri.path();
-        if let Some(serv) = self.routes.route(method, path) {
-            match serv {
-                RouteService::NotSend(serv) => serv.borrow_mut().call(req),
-                RouteService::Send(serv) => serv.borrow_mut().call(req),
-            }
-        } else {
-            let e = Error::from(ErrorKind::InvalidEndpoint);
-            Box::new(ok(resp_serv_err(e, hyper::StatusCode::NOT_FOUND)))
-        }
+        Box::new(
+            self.pipeline
+                .call(&self.routes.table, req, |serv, req| serv.call(req)),
+        )
     }
 }
 
@@ -250,3 +1265,290 @@ impl hyper::service::NewService for Server {
         ok(self.clone())
     }
 }
+
+/// `Server` which dispatches requests from multiple threads.
+#[derive(Clone)]
+struct ServerSend {
+    routes: Arc<RouteTable<HyperServiceSync>>,
+    pipeline: Pipeline,
+}
+
+impl ServerSend {
+    fn new(server: &Server) -> Result<Self> {
+        Ok(Self {
+            routes: Arc::new(server.routes.send_table()?),
+            pipeline: server.pipeline.clone(),
+        })
+    }
+}
+
+impl hyper::service::Service for ServerSend {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // a clone of the route service, threads do not wait for each other
+        Box::new(
+            self.pipeline
+                .call(&self.routes, req, |serv, req| serv.clone().call(req)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::oneshot;
+    use futures::{stream, task, Async};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::StatusCode;
+    use tokio::runtime::current_thread::Runtime;
+
+    use async;
+    use client::{Client, RemoteError};
+    use sync;
+    use test::{TestClient, TestResponse};
+    use Empty;
+
+    /// reply which is not ready on the first poll
+    fn pending_once() -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+        let mut polled = false;
+        poll_fn(move || {
+            if polled {
+                return Ok(Async::Ready(Response::new(Body::empty())));
+            }
+            polled = true;
+            task::current().notify();
+            Ok(Async::NotReady)
+        })
+    }
+
+    #[test]
+    fn deadline_ignores_timer_error() {
+        // without a runtime there is no timer, and the delay fails
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let resp = with_deadline(deadline, ErrorReply::default(), pending_once()).wait();
+        assert_eq!(resp.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn shutdown_ignores_timer_error() {
+        let shutdown = Shutdown::new(ok(()), Duration::from_secs(60));
+        let resp = shutdown.guard(ErrorReply::default(), pending_once()).wait();
+        assert_eq!(resp.unwrap().status(), StatusCode::OK);
+    }
+
+    /// server which is shut down with `grace` by the first request of `/slow`, which replies
+    /// after `delay`
+    fn shutdown_on_request(delay: Duration, grace: Duration) -> Server {
+        let (tx, rx) = oneshot::channel();
+        let tx = RefCell::new(Some(tx));
+        let slow = async::serv(
+            move |_req: Empty| -> Box<Future<Item = String, Error = Error>> {
+                if let Some(tx) = tx.borrow_mut().take() {
+                    let _ = tx.send(());
+                }
+                let reply = Delay::new(Instant::now() + delay).then(|_| Ok("done".to_owned()));
+                Box::new(reply)
+            },
+        );
+        let mut routes = Routes::new();
+        routes.push(hyper::Method::GET, "/slow", slow);
+        Server::new(routes).with_graceful_shutdown(rx.map_err(|_| ()), grace)
+    }
+
+    /// reply of `/slow` from `server`, the server must have stopped by then
+    fn shutdown_reply(server: Server) -> std::result::Result<String, RemoteError> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = Client::new(format!("http://{}", addr).parse().unwrap()).unwrap();
+        let started = Instant::now();
+        let mut rt = Runtime::new().unwrap();
+        let f = lazy(move || {
+            let server = server.run_listener(listener);
+            let reply = client.get::<_, String>("/slow", &Empty {}).then(Ok);
+            reply.join(server.map_err(RemoteError::from))
+        });
+        let (reply, ()) = rt.block_on(f).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(
+            std::net::TcpStream::connect(addr).is_err(),
+            "still accepting"
+        );
+        reply
+    }
+
+    #[test]
+    fn shutdown_drains_in_flight_requests() {
+        let delay = Duration::from_millis(100);
+        let server = shutdown_on_request(delay, Duration::from_secs(10));
+        assert_eq!(shutdown_reply(server).unwrap(), "done");
+    }
+
+    #[test]
+    fn shutdown_deadline_cuts_off_requests() {
+        let grace = Duration::from_millis(100);
+        let server = shutdown_on_request(Duration::from_secs(10), grace);
+        match shutdown_reply(server) {
+            Err(RemoteError::Reply { status, reason, .. }) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(reason, "shutting_down");
+            }
+            res => panic!("unexpected reply: {:?}", res),
+        }
+    }
+
+    #[cfg(feature = "uds")]
+    #[test]
+    fn shutdown_removes_socket_file() {
+        let url = socket_url("shutdown");
+        let path = std::path::PathBuf::from(url.path());
+        let server =
+            Server::new(Routes::new()).with_graceful_shutdown(ok(()), Duration::from_secs(1));
+        let mut rt = Runtime::new().unwrap();
+        let f = lazy(|| {
+            let server = server.run_uds(url.clone());
+            assert!(path.exists());
+            server
+        });
+        rt.block_on(f).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn invalid_urls_fail() {
+        for url in &["ftp://localhost:80", "http://localhost"] {
+            let server = Server::new(Routes::new());
+            assert!(server.run(url.parse().unwrap()).wait().is_err(), "{}", url);
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Rename {
+        name: String,
+    }
+
+    fn limited() -> TestClient {
+        let rename = || sync::serv(|req: Rename| -> Result<String> { Ok(req.name) });
+        let mut routes = Routes::new();
+        routes.push(hyper::Method::POST, "/small", rename());
+        routes.push(hyper::Method::POST, "/large", rename());
+        routes.set_body_limit(hyper::Method::POST, "/small", 4);
+        TestClient::new(Server::new(routes).with_body_limit(16))
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected() {
+        let mut client = limited();
+        let bob = Rename {
+            name: "bob".to_owned(),
+        };
+        assert_eq!(
+            client.post::<_, String>("/large", &bob).into_result(),
+            "bob"
+        );
+        client
+            .post::<_, String>("/small", &bob)
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE)
+            .assert_reason("body_too_large");
+        let robert = Rename {
+            name: "robert robertson".to_owned(),
+        };
+        client
+            .post::<_, String>("/large", &robert)
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn chunked_bodies_over_the_limit_are_rejected() {
+        let chunks = vec!["{\"name\":", "\"robert robertson\"}"];
+        let body = Body::wrap_stream(stream::iter_ok::<_, std::io::Error>(chunks));
+        let req = Request::post("/large")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        let resp: TestResponse<String> = limited().request(req);
+        resp.assert_status(StatusCode::PAYLOAD_TOO_LARGE)
+            .assert_reason("body_too_large");
+    }
+
+    #[test]
+    fn slow_handlers_time_out() {
+        let never = || {
+            async::serv(|_req: Empty| -> Box<Future<Item = u32, Error = Error>> {
+                Box::new(empty())
+            })
+        };
+        let mut routes = Routes::new();
+        routes.push(hyper::Method::GET, "/never", never());
+        routes.push(hyper::Method::GET, "/short", never());
+        routes.push(
+            hyper::Method::GET,
+            "/now",
+            sync::serv(|_req: Empty| -> Result<u32> { Ok(1) }),
+        );
+        routes.set_timeout(hyper::Method::GET, "/short", Duration::from_millis(10));
+        let server = Server::new(routes).with_timeout(Duration::from_millis(100));
+        let mut client = TestClient::new(server);
+
+        assert_eq!(client.get::<_, u32>("/now", &Empty {}).into_result(), 1);
+        let start = Instant::now();
+        client
+            .get::<_, u32>("/short", &Empty {})
+            .assert_status(StatusCode::GATEWAY_TIMEOUT)
+            .assert_reason("timeout");
+        client
+            .get::<_, u32>("/never", &Empty {})
+            .assert_status(StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    /// url of a unix socket in the temp dir, unique for test `name`
+    #[cfg(feature = "uds")]
+    fn socket_url(name: &str) -> url::Url {
+        let path = std::env::temp_dir().join(format!("serv-{}-{}.sock", name, std::process::id()));
+        format!("http+unix://{}", path.display()).parse().unwrap()
+    }
+
+    #[cfg(feature = "uds")]
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Greet {
+        name: String,
+    }
+
+    #[cfg(feature = "uds")]
+    #[test]
+    fn threaded_routes_are_replied() {
+        let greet = sync::serv_state_send(
+            "hello".to_owned(),
+            |greeting: &String, req: Greet| -> Result<String> {
+                Ok(format!("{} {}", greeting, req.name))
+            },
+        );
+        let mut routes = Routes::new();
+        routes.push_send(hyper::Method::GET, "/greet", greet);
+        let server = Server::new(routes);
+
+        let url = socket_url("threaded");
+        let mut pool = tokio::runtime::Runtime::new().unwrap();
+        pool.spawn(
+            server
+                .run_threaded(url.clone())
+                .map_err(|e| panic!("server failed: {}", e)),
+        );
+
+        let client = Client::new(url).unwrap();
+        let replies = ["alice", "bob"].iter().map(|name| {
+            let req = Greet {
+                name: (*name).to_owned(),
+            };
+            client.get::<_, String>("/greet", &req)
+        });
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let replies = rt.block_on(join_all(replies)).unwrap();
+        assert_eq!(replies, vec!["hello alice", "hello bob"]);
+        pool.shutdown_now().wait().unwrap();
+    }
+}
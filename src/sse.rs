@@ -0,0 +1,426 @@
+use std::fmt::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::Chunk;
+use tokio::timer::Interval;
+
+use super::*;
+use stream;
+
+/// Interval of keep-alive comments while the handler stream is idle.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// `serv_state` builds `HyperService` which replies `text/event-stream` with events from the
+/// stream returned by `F`. `F` also receives `Last-Event-ID` of a reconnecting client.
+/// The stream is not `Send` and needs a current-thread executor, see `stream`.
+pub fn serv_state<F, S, Req, Resp, E>(state: S, f: F) -> HyperService
+where
+    F: for<'a> Fn(&'a S, Option<String>, Req) -> Box<Stream<Item = Resp, Error = E>> + 'static,
+    S: 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoEvent + 'static,
+    E: HandlerError,
+{
+    Box::new(SseService::new(move |last_event_id, req| {
+        f(&state, last_event_id, req)
+    }))
+}
+
+/// `serv` builds `HyperService` which replies events from the stream returned by `F`. It runs
+/// on a current-thread executor, see `serv_state`.
+pub fn serv<F, Req, Resp, E>(f: F) -> HyperService
+where
+    F: Fn(Option<String>, Req) -> Box<Stream<Item = Resp, Error = E>> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoEvent + 'static,
+    E: HandlerError,
+{
+    Box::new(SseService::new(f))
+}
+
+/// `serv_state_send` builds `HyperServiceSync` which replies events from the stream returned
+/// by `F`.
+pub fn serv_state_send<F, S, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a S, Option<String>, Req) -> Box<Stream<Item = Resp, Error = E> + Send>
+        + Send
+        + Sync
+        + 'static,
+    S: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoEvent + Send + 'static,
+    E: HandlerError + Send,
+{
+    Box::new(SseServiceSend::new(move |last_event_id, req| {
+        f(&state, last_event_id, req)
+    }))
+}
+
+/// `serv_send` builds `HyperServiceSync` which replies events from the stream returned by `F`.
+pub fn serv_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: Fn(Option<String>, Req) -> Box<Stream<Item = Resp, Error = E> + Send>
+        + Send
+        + Sync
+        + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoEvent + Send + 'static,
+    E: HandlerError + Send,
+{
+    Box::new(SseServiceSend::new(f))
+}
+
+/// Server-sent event with optional id, event name and reconnection time. `data` is encoded as
+/// JSON.
+#[derive(Debug)]
+pub struct Event<T> {
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<Duration>,
+    data: T,
+}
+
+impl<T> Event<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            id: None,
+            event: None,
+            retry: None,
+            data,
+        }
+    }
+
+    /// id which the client sends back as `Last-Event-ID` on reconnect
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// event name, `message` if not set
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// how long the client waits before reconnecting
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// field values can not span lines
+fn single_line(s: &str) -> String {
+    s.chars().filter(|c| *c != '\n' && *c != '\r').collect()
+}
+
+/// Item of SSE handler streams, either plain `Serialize` data or `Event`.
+pub trait IntoEvent {
+    type Data: serde::Serialize;
+
+    fn into_event(self) -> Event<Self::Data>;
+}
+
+impl<T> IntoEvent for T
+where
+    T: serde::Serialize,
+{
+    type Data = T;
+
+    fn into_event(self) -> Event<T> {
+        Event::new(self)
+    }
+}
+
+impl<T> IntoEvent for Event<T>
+where
+    T: serde::Serialize,
+{
+    type Data = T;
+
+    fn into_event(self) -> Event<T> {
+        self
+    }
+}
+
+fn encode_event<T>(event: Event<T>) -> Result<Chunk, Error>
+where
+    T: serde::Serialize,
+{
+    let data = serde_json::to_string(&event.data).map_err(ErrorKind::EncodeJson)?;
+    let mut buf = String::new();
+    if let Some(event) = event.event {
+        let _ = writeln!(buf, "event: {}", event);
+    }
+    if let Some(id) = event.id {
+        let _ = writeln!(buf, "id: {}", id);
+    }
+    if let Some(retry) = event.retry {
+        let _ = writeln!(buf, "retry: {}", retry.as_millis());
+    }
+    let _ = write!(buf, "data: {}\n\n", data);
+    Ok(buf.into())
+}
+
+/// `error` event with the error reply of `e`
+fn encode_error<E>(e: E) -> Chunk
+where
+    E: Debug + std::error::Error,
+{
+    let reply = reply::ServiceReply::<(), E>::from(e);
+    let data = serde_json::to_string(&reply).unwrap_or_default();
+    format!("event: error\ndata: {}\n\n", data).into()
+}
+
+/// Handler stream encoded as events, with keep-alive comments while it is idle. The stream
+/// ends after an `error` event if the handler stream fails.
+struct EventStream<St> {
+    inner: St,
+    /// `None` if the timer failed
+    keep_alive: Option<Interval>,
+    done: bool,
+}
+
+impl<St> EventStream<St> {
+    fn new(inner: St) -> Self {
+        Self {
+            inner,
+            keep_alive: Some(Interval::new(Instant::now() + KEEP_ALIVE, KEEP_ALIVE)),
+            done: false,
+        }
+    }
+}
+
+impl<St, E> Stream for EventStream<St>
+where
+    St: Stream<Error = E>,
+    St::Item: IntoEvent,
+    E: From<Error> + Debug + std::error::Error,
+{
+    type Item = Chunk;
+    type Error = std::io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, std::io::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let e = match self.inner.poll() {
+            Ok(Async::Ready(Some(item))) => match encode_event(item.into_event()) {
+                Ok(chunk) => return Ok(Async::Ready(Some(chunk))),
+                Err(e) => E::from(e),
+            },
+            Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+            Err(e) => e,
+            Ok(Async::NotReady) => {
+                match self.keep_alive.as_mut().map(|keep_alive| keep_alive.poll()) {
+                    Some(Ok(Async::Ready(Some(_)))) => {
+                        return Ok(Async::Ready(Some(Chunk::from(": keep-alive\n\n"))));
+                    }
+                    // events are still sent as the handler stream yields them
+                    Some(Err(e)) => {
+                        warn!("failed to start keep-alive timer: {}", e);
+                        self.keep_alive = None;
+                    }
+                    _ => {}
+                }
+                return Ok(Async::NotReady);
+            }
+        };
+        self.done = true;
+        Ok(Async::Ready(Some(encode_error(e))))
+    }
+}
+
+fn last_event_id(ctx: &RequestContext) -> Option<String> {
+    let id = ctx.headers().get("last-event-id")?;
+    id.to_str().ok().map(|id| id.to_owned())
+}
+
+fn event_stream_response(body: Body) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(CACHE_CONTROL, "no-cache")
+        .header(CONTENT_TYPE, "text/event-stream")
+        .body(body)
+        .unwrap_or_else(|e| resp_serv_err(e, hyper::StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// `SseService` implements `hyper::service::Service` for SSE handler `F`
+struct SseService<F, Req> {
+    f: SyncObj<F>,
+    _req: PhantomData<fn(Req)>,
+}
+
+impl<F, Req> SseService<F, Req> {
+    fn new(f: F) -> Self {
+        Self {
+            f: SyncObj::new(f),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req, St, E> hyper::service::Service for SseService<F, Req>
+where
+    F: Fn(Option<String>, Req) -> St + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    St: Stream<Error = E> + 'static,
+    St::Item: IntoEvent,
+    E: HandlerError,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFuture;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let f = self.f.clone();
+        let f = parse_req(req).then(move |res| {
+            let body = res
+                .and_then(|(ctx, req)| stream::body(EventStream::new(f(last_event_id(&ctx), req))));
+            let resp = match body {
+                Ok(body) => event_stream_response(body),
+                Err(e) => {
+                    let e = E::from(e);
+                    let status = e.status();
+                    resp_serv_err_as(e, status, codec)
+                }
+            };
+            Ok(resp)
+        });
+        Box::new(f)
+    }
+}
+
+/// `SseServiceSend` implements `hyper::service::Service` for `Send` and `Sync` SSE handler `F`
+struct SseServiceSend<F, Req> {
+    f: Arc<F>,
+    _req: PhantomData<fn(Req)>,
+}
+
+impl<F, Req> SseServiceSend<F, Req> {
+    fn new(f: F) -> Self {
+        Self {
+            f: Arc::new(f),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req> Clone for SseServiceSend<F, Req> {
+    fn clone(&self) -> Self {
+        Self {
+            f: self.f.clone(),
+            _req: Default::default(),
+        }
+    }
+}
+
+impl<F, Req, St, E> hyper::service::Service for SseServiceSend<F, Req>
+where
+    F: Fn(Option<String>, Req) -> St + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    St: Stream<Error = E> + Send + 'static,
+    St::Item: IntoEvent,
+    E: HandlerError + Send,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let f = self.f.clone();
+        let f = parse_req_send(req).then(move |res| {
+            let resp = match res {
+                Ok((ctx, req)) => {
+                    let events = EventStream::new(f(last_event_id(&ctx), req));
+                    event_stream_response(stream::body_send(events))
+                }
+                Err(e) => {
+                    let e = E::from(e);
+                    let status = e.status();
+                    resp_serv_err_as(e, status, codec)
+                }
+            };
+            Ok(resp)
+        });
+        Box::new(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::{iter_ok, iter_result};
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use test::TestClient;
+    use Empty;
+
+    fn server() -> Server {
+        let events = serv(|last_event_id: Option<String>, _req: Empty| {
+            let from = last_event_id.map_or(0, |id| id.parse().unwrap_or(0));
+            let events = (from + 1..from + 3).map(|id: u32| Event::new(id).id(&id.to_string()));
+            Box::new(iter_ok::<_, Error>(events)) as Box<Stream<Item = _, Error = _>>
+        });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/events", events);
+        Server::new(routes)
+    }
+
+    #[test]
+    fn events_are_streamed() {
+        let mut client = TestClient::new(server());
+        let req = Request::get("/events")
+            .header("last-event-id", "2")
+            .body(Body::empty())
+            .unwrap();
+        let resp = client.request::<()>(req);
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(resp.body(), &b"id: 3\ndata: 3\n\nid: 4\ndata: 4\n\n"[..]);
+    }
+
+    #[test]
+    fn events_are_framed() {
+        let events = vec![
+            Ok(Event::new("a\nb")
+                .event("greet\n")
+                .id("1\r\n")
+                .retry(Duration::from_secs(3))),
+            Ok(Event::new("c")),
+            Err(Error::from(ErrorKind::InvalidEndpoint)),
+            Ok(Event::new("d")),
+        ];
+        let chunks: Vec<Chunk> = EventStream::new(iter_result(events))
+            .collect()
+            .wait()
+            .unwrap();
+        let chunks: Vec<&str> = chunks
+            .iter()
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[0],
+            "event: greet\nid: 1\nretry: 3000\ndata: \"a\\nb\"\n\n"
+        );
+        assert_eq!(chunks[1], "data: \"c\"\n\n");
+        assert!(
+            chunks[2].starts_with("event: error\ndata: "),
+            "{}",
+            chunks[2]
+        );
+        let error = chunks[2]
+            .trim_start_matches("event: error\ndata: ")
+            .trim_end_matches("\n\n");
+        let error: serde_json::Value = serde_json::from_str(error).unwrap();
+        assert_eq!(error["reason"], "invalid_endpoint");
+    }
+}
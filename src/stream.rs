@@ -0,0 +1,127 @@
+//! Streamed replies of `sse`, `ndjson` and `ws`.
+//!
+//! Handler streams and futures of `serv_state` and `serv` are not `Send`, so they are driven
+//! by a task on the current-thread executor which runs the service, as with `Server::run` on
+//! `tokio::runtime::current_thread`. On other executors, e.g. of `Server::run_threaded`, the
+//! task cannot be spawned and the request gets `500 Internal Server Error`. Handlers of
+//! `serv_state_send` and `serv_send` are `Send` and run on any executor.
+
+use std;
+
+use futures::*;
+use hyper::body::Sender;
+use hyper::{Body, Chunk};
+#[cfg(feature = "ws")]
+use tokio::executor::{DefaultExecutor, Executor};
+use tokio_current_thread as current_thread;
+
+use error::*;
+
+/// Body which streams `stream`, for streams which are not `Send`. The stream is driven by a
+/// task on the current-thread executor and stops when the client goes away, see `spawn_local`.
+pub(crate) fn body<S>(stream: S) -> Result<Body>
+where
+    S: Stream<Item = Chunk, Error = std::io::Error> + 'static,
+{
+    let (tx, body) = Body::channel();
+    spawn_local(Forward {
+        stream,
+        tx,
+        pending: None,
+    })?;
+    Ok(body)
+}
+
+/// Body which streams `stream`, polled by hyper.
+pub(crate) fn body_send<S>(stream: S) -> Body
+where
+    S: Stream<Item = Chunk, Error = std::io::Error> + Send + 'static,
+{
+    Body::wrap_stream(stream)
+}
+
+/// Spawn `f` on the current-thread executor which runs the service, e.g. of `Server::run`.
+/// Fails if the service runs on another executor, e.g. a thread pool.
+pub(crate) fn spawn_local<F>(f: F) -> Result<()>
+where
+    F: Future<Item = (), Error = ()> + 'static,
+{
+    current_thread::TaskExecutor::current()
+        .spawn_local(Box::new(f))
+        .map_err(|e| ErrorKind::Spawn(e.to_string()).into())
+}
+
+/// Spawn `f` on the executor which runs the service, e.g. of `Server::run_threaded`.
+#[cfg(feature = "ws")]
+pub(crate) fn spawn<F>(f: F) -> Result<()>
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    DefaultExecutor::current()
+        .spawn(Box::new(f))
+        .map_err(|e| ErrorKind::Spawn(e.to_string()).into())
+}
+
+/// forward `stream` to `tx`, waiting for `tx` to be ready before polling the next chunk
+struct Forward<S> {
+    stream: S,
+    tx: Sender,
+    pending: Option<Chunk>,
+}
+
+impl<S> Future for Forward<S>
+where
+    S: Stream<Item = Chunk>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(chunk) = self.pending.take() {
+                match self.tx.poll_ready() {
+                    Ok(Async::Ready(())) => {
+                        if self.tx.send_data(chunk).is_err() {
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                    Ok(Async::NotReady) => {
+                        self.pending = Some(chunk);
+                        return Ok(Async::NotReady);
+                    }
+                    // client went away
+                    Err(_e) => return Ok(Async::Ready(())),
+                }
+            }
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.pending = Some(chunk),
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::iter_ok;
+
+    #[test]
+    fn other_executor_is_spawn_error() {
+        let chunks = iter_ok::<_, std::io::Error>(vec![Chunk::from("a")]);
+        match body(chunks) {
+            Err(Error(ErrorKind::Spawn(_), _)) => {}
+            res => panic!("unexpected body: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn current_thread_executor_streams_body() {
+        let chunks = iter_ok::<_, std::io::Error>(vec![Chunk::from("a"), Chunk::from("b")]);
+        let mut rt = current_thread::CurrentThread::new();
+        let body = rt.block_on(lazy(|| body(chunks))).unwrap();
+        let body = rt.block_on(body.concat2()).unwrap();
+        assert_eq!(body.as_ref(), b"ab");
+    }
+}
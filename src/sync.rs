@@ -9,8 +9,8 @@ where
     F: for<'a> Fn(&'a S, Req) -> Result<Resp, E> + 'static,
     S: 'static,
     Req: for<'de> serde::Deserialize<'de> + 'static,
-    Resp: serde::Serialize + 'static,
-    E: From<Error> + Debug + std::error::Error + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
 {
     reply::ServiceReply::serv_state_sync(state, f)
 }
@@ -20,8 +20,90 @@ pub fn serv<F, Req, Resp, E>(f: F) -> HyperService
 where
     F: Fn(Req) -> Result<Resp, E> + 'static,
     Req: for<'de> serde::Deserialize<'de> + 'static,
-    Resp: serde::Serialize + 'static,
-    E: From<Error> + Debug + std::error::Error + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
 {
     reply::ServiceReply::serv_sync(f)
 }
+
+/// `serv_state_send` builds `HyperServiceSync` with given function `F` and state `S`.
+pub fn serv_state_send<F, S, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a S, Req) -> Result<Resp, E> + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_state_sync_send(state, f)
+}
+
+/// `serv_send` builds `HyperServiceSync` with given function `F`.
+pub fn serv_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: Fn(Req) -> Result<Resp, E> + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_sync_send(f)
+}
+
+/// `serv_ctx` builds `HyperService` with given function `F`, which also receives
+/// `RequestContext` of the request. State is captured by `F`.
+pub fn serv_ctx<F, Req, Resp, E>(f: F) -> HyperService
+where
+    F: for<'a> Fn(&'a RequestContext, Req) -> Result<Resp, E> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
+{
+    reply::ServiceReply::serv_ctx(move |ctx: &RequestContext, req| result(f(ctx, req)))
+}
+
+/// `serv_ctx_send` builds `HyperServiceSync` with given function `F`, which also receives
+/// `RequestContext` of the request.
+pub fn serv_ctx_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a RequestContext, Req) -> Result<Resp, E> + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_ctx_send(move |ctx: &RequestContext, req| result(f(ctx, req)))
+}
+
+/// `serv_state_auth` builds `HyperService` with given function `F` and state `S`. `F` also
+/// receives the principal authenticated by `Auth`, requests without one get `401
+/// Unauthorized`.
+pub fn serv_state_auth<F, S, P, Req, Resp, E>(state: S, f: F) -> HyperService
+where
+    F: for<'a, 'b> Fn(&'a S, &'b P, Req) -> Result<Resp, E> + 'static,
+    S: 'static,
+    P: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: IntoResponse + 'static,
+    E: HandlerError,
+{
+    reply::ServiceReply::serv_ctx(move |ctx: &RequestContext, req| match ctx.principal() {
+        Some(principal) => result(f(&state, principal, req)),
+        None => err(Error::from(ErrorKind::Unauthorized).into()),
+    })
+}
+
+/// `serv_state_auth_send` builds `HyperServiceSync` with given function `F` and state `S`.
+/// `F` also receives the principal authenticated by `Auth`.
+pub fn serv_state_auth_send<F, S, P, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a, 'b> Fn(&'a S, &'b P, Req) -> Result<Resp, E> + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    P: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: IntoResponse + Send + 'static,
+    E: HandlerError + Send,
+{
+    reply::ServiceReply::serv_ctx_send(move |ctx: &RequestContext, req| match ctx.principal() {
+        Some(principal) => result(f(&state, principal, req)),
+        None => err(Error::from(ErrorKind::Unauthorized).into()),
+    })
+}
@@ -0,0 +1,215 @@
+use futures::{Future, Stream};
+use hyper;
+use hyper::service::Service;
+use hyper::{Body, HeaderMap, Request, StatusCode};
+use serde;
+use serde_json;
+use tokio::runtime::current_thread::Runtime;
+
+use client;
+use reply::RemoteReply;
+use server::Server;
+
+/// `TestClient` calls `Server` in process, without binding a socket. Requests are encoded as
+/// JSON, query string for `GET` and `DELETE`, and replies are decoded from the reply envelope.
+///
+/// `TestClient` panics on failures which are not replies of the server, e.g. failing to
+/// encode `Req`, as it is meant to be used in tests.
+pub struct TestClient {
+    server: Server,
+    rt: Runtime,
+}
+
+impl TestClient {
+    pub fn new(server: Server) -> Self {
+        Self {
+            server,
+            rt: Runtime::new().expect("failed to create runtime"),
+        }
+    }
+
+    pub fn get<Req, Resp>(&mut self, path: &str, req: &Req) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(hyper::Method::GET, path, req)
+    }
+
+    pub fn delete<Req, Resp>(&mut self, path: &str, req: &Req) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(hyper::Method::DELETE, path, req)
+    }
+
+    pub fn post<Req, Resp>(&mut self, path: &str, req: &Req) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(hyper::Method::POST, path, req)
+    }
+
+    pub fn put<Req, Resp>(&mut self, path: &str, req: &Req) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(hyper::Method::PUT, path, req)
+    }
+
+    pub fn patch<Req, Resp>(&mut self, path: &str, req: &Req) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(hyper::Method::PATCH, path, req)
+    }
+
+    /// Call `method` at `path` with `req`, encoded the way handlers decode it. `req` of `GET`
+    /// and `DELETE` should be a struct, e.g. `Empty`.
+    pub fn call<Req, Resp>(
+        &mut self,
+        method: hyper::Method,
+        path: &str,
+        req: &Req,
+    ) -> TestResponse<Resp>
+    where
+        Req: serde::Serialize,
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        let req = client::encode_req(method, path, req).expect("failed to encode request");
+        self.request(req)
+    }
+
+    /// Send `req` as is, e.g. to set headers.
+    pub fn request<Resp>(&mut self, req: Request<Body>) -> TestResponse<Resp>
+    where
+        Resp: for<'de> serde::Deserialize<'de>,
+    {
+        let f = self.server.call(req).and_then(|resp| {
+            let (parts, body) = resp.into_parts();
+            body.concat2().map(move |body| (parts, body))
+        });
+        let (parts, body) = self.rt.block_on(f).expect("failed to call server");
+        let reply = serde_json::from_slice(&body).ok();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.to_vec(),
+            reply,
+        }
+    }
+}
+
+/// Reply of `TestClient`. `result` and `reason` are set if the body is the reply envelope.
+#[derive(Debug)]
+pub struct TestResponse<T> {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    reply: Option<RemoteReply<T>>,
+}
+
+impl<T> TestResponse<T> {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// `result` of an `ok` reply
+    pub fn result(&self) -> Option<&T> {
+        match self.reply {
+            Some(RemoteReply::Ok { ref result }) => Some(result),
+            _ => None,
+        }
+    }
+
+    /// `reason` of an `error` reply
+    pub fn reason(&self) -> Option<&str> {
+        match self.reply {
+            Some(RemoteReply::Err { ref reason, .. }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// `msg` of an `error` reply, only set in debug builds
+    pub fn msg(&self) -> Option<&str> {
+        match self.reply {
+            Some(RemoteReply::Err { ref msg, .. }) => msg.as_ref().map(|msg| msg.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "unexpected status, body: {}",
+            String::from_utf8_lossy(&self.body)
+        );
+        self
+    }
+
+    pub fn assert_reason(&self, reason: &str) -> &Self {
+        assert_eq!(
+            self.reason(),
+            Some(reason),
+            "unexpected reason, body: {}",
+            String::from_utf8_lossy(&self.body)
+        );
+        self
+    }
+
+    /// `result` of an `ok` reply, panics on other replies.
+    pub fn into_result(self) -> T {
+        match self.reply {
+            Some(RemoteReply::Ok { result }) => result,
+            _ => panic!(
+                "not an ok reply, status: {}, body: {}",
+                self.status,
+                String::from_utf8_lossy(&self.body)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+
+    use error::Result;
+    use server::Routes;
+    use sync;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Rename {
+        name: String,
+    }
+
+    fn client() -> TestClient {
+        let rename = sync::serv(|req: Rename| -> Result<String> { Ok(req.name) });
+        let mut routes = Routes::new();
+        routes.push(Method::PATCH, "/name", rename);
+        TestClient::new(Server::new(routes))
+    }
+
+    #[test]
+    fn patch_body_is_decoded() {
+        let req = Rename {
+            name: "bob".to_owned(),
+        };
+        let resp = client().patch::<_, String>("/name", &req);
+        assert_eq!(resp.into_result(), "bob");
+    }
+}
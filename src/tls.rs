@@ -0,0 +1,550 @@
+use std;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Stream};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+    ResolvesServerCert, RootCertStore, ServerConfig, ServerSession, Session, SignatureScheme,
+};
+use tokio::net::TcpStream;
+use tokio::timer::{Delay, Interval, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use webpki;
+use x509_parser;
+
+use error::*;
+
+/// Time for clients to complete the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshakes which run at the same time, further connections wait in the listen backlog.
+pub const MAX_HANDSHAKES: usize = 256;
+
+/// Pause of accepting connections after an accept error, e.g. too many open files.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// TLS settings of `Server` for `https://` urls, see `Server::with_tls`.
+///
+/// Certificate and key are read from PEM files. Clones share the certificate, so `reload` or
+/// `watch` on a clone replaces the certificate of the running server for new connections.
+#[derive(Clone)]
+pub struct TlsConfig {
+    certs: Arc<CertResolver>,
+    client_ca: Option<RootCertStore>,
+    client_cert_optional: bool,
+}
+
+impl TlsConfig {
+    /// Serve certificate chain `cert` with private key `key`, PKCS#8 or RSA.
+    pub fn new<P, Q>(cert: P, key: Q) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let certs = CertResolver::new(cert.as_ref().to_owned(), key.as_ref().to_owned())?;
+        Ok(Self {
+            certs: Arc::new(certs),
+            client_ca: None,
+            client_cert_optional: false,
+        })
+    }
+
+    /// Require client certificates issued by CAs in PEM file `ca` (mTLS). The subject of the
+    /// client certificate is read with `RequestContext::client_cert_subject`.
+    pub fn client_ca<P>(mut self, ca: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let ca = ca.as_ref();
+        let mut roots = RootCertStore::empty();
+        let added = roots
+            .add_pem_file(&mut BufReader::new(File::open(ca)?))
+            .map_err(|_| format!("invalid CA file {}", ca.display()))?
+            .0;
+        if added == 0 {
+            bail!("no CA certificates in {}", ca.display());
+        }
+        self.client_ca = Some(roots);
+        Ok(self)
+    }
+
+    /// Also accept clients without certificate, certificates which are presented are still
+    /// verified.
+    pub fn client_cert_optional(mut self) -> Self {
+        self.client_cert_optional = true;
+        self
+    }
+
+    /// Read certificate and key files again. On failure the current certificate is kept.
+    pub fn reload(&self) -> Result<()> {
+        self.certs.reload()
+    }
+
+    /// Reload certificate and key when the files change, checked every `interval`. Run the
+    /// returned future on the runtime of the server.
+    pub fn watch(&self, interval: Duration) -> Box<Future<Item = (), Error = ()> + Send> {
+        let certs = self.certs.clone();
+        let f = Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| error!("tls: watch timer failed: {}", e))
+            .for_each(move |_| {
+                if certs.changed() {
+                    match certs.reload() {
+                        Ok(()) => info!("tls: reloaded {}", certs.cert.display()),
+                        Err(e) => warn!("tls: failed to reload {}: {}", certs.cert.display(), e),
+                    }
+                }
+                Ok(())
+            });
+        Box::new(f)
+    }
+
+    /// rustls config which negotiates `protocols` with ALPN
+    pub(crate) fn server_config(&self, protocols: Vec<Vec<u8>>) -> Arc<ServerConfig> {
+        let verifier = match (self.client_ca.clone(), self.client_cert_optional) {
+            (Some(roots), false) => AllowAnyAuthenticatedClient::new(roots),
+            (Some(roots), true) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            (None, _) => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = self.certs.clone();
+        config.set_protocols(&protocols);
+        Arc::new(config)
+    }
+}
+
+/// Certificate and key loaded from `cert` and `key`, replaced on reload.
+struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+struct Loaded {
+    certified: CertifiedKey,
+    modified: Option<SystemTime>,
+}
+
+impl CertResolver {
+    fn new(cert: PathBuf, key: PathBuf) -> Result<Self> {
+        let loaded = load(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn reload(&self) -> Result<()> {
+        let loaded = load(&self.cert, &self.key)?;
+        *self.loaded.write().expect("poisoned") = loaded;
+        Ok(())
+    }
+
+    /// whether files are modified since they were loaded
+    fn changed(&self) -> bool {
+        let modified = modified(&self.cert, &self.key);
+        modified.is_some() && modified != self.loaded.read().expect("poisoned").modified
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        _server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.loaded.read().expect("poisoned").certified.clone())
+    }
+}
+
+fn load(cert: &Path, key: &Path) -> Result<Loaded> {
+    // before reading, so that a file written in between is loaded again
+    let modified = modified(cert, key);
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| format!("invalid certificate file {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", cert.display());
+    }
+
+    let mut pem = Vec::new();
+    File::open(key)?.read_to_end(&mut pem)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    }
+    let signing_key = match keys.first() {
+        Some(der) => sign::any_supported_type(der)
+            .map_err(|_| format!("unsupported private key in {}", key.display()))?,
+        None => bail!("no private key in {}", key.display()),
+    };
+
+    Ok(Loaded {
+        certified: CertifiedKey::new(certs, Arc::new(signing_key)),
+        modified,
+    })
+}
+
+/// latest modification time of `cert` and `key`
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|m| m.modified()).ok()?;
+    Some(std::cmp::max(cert, key))
+}
+
+/// Verified client certificate of the connection, stored in request extensions by `Server`.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientCert {
+    pub(crate) subject: String,
+}
+
+/// TLS connections accepted from `incoming`. Up to `MAX_HANDSHAKES` handshakes run
+/// concurrently, connections which fail the handshake are dropped. Accept errors are logged and
+/// accepting is paused for `ACCEPT_ERROR_DELAY`.
+pub(crate) struct TlsIncoming<I> {
+    incoming: I,
+    config: Arc<ServerConfig>,
+    handshakes: FuturesUnordered<Timeout<Handshake<TcpStream>>>,
+    /// end of the pause after an accept error
+    paused: Option<Delay>,
+}
+
+impl<I> TlsIncoming<I>
+where
+    I: Stream<Item = TcpStream, Error = io::Error>,
+{
+    pub(crate) fn new(incoming: I, config: Arc<ServerConfig>) -> Self {
+        Self {
+            incoming,
+            config,
+            handshakes: FuturesUnordered::new(),
+            paused: None,
+        }
+    }
+
+    /// whether to accept connections, `false` while paused after an accept error
+    fn accepting(&mut self) -> bool {
+        if self.handshakes.len() >= MAX_HANDSHAKES {
+            return false;
+        }
+        match self.paused.as_mut().map(|paused| paused.poll()) {
+            Some(Ok(Async::NotReady)) => false,
+            // timer errors end the pause
+            _ => {
+                self.paused = None;
+                true
+            }
+        }
+    }
+}
+
+impl<I> Stream for TlsIncoming<I>
+where
+    I: Stream<Item = TcpStream, Error = io::Error>,
+{
+    type Item = TlsStream<TcpStream>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            while self.accepting() {
+                match self.incoming.poll() {
+                    Ok(Async::Ready(Some(conn))) => {
+                        let handshake = Handshake(Some(TlsStream::new(conn, &self.config)));
+                        self.handshakes
+                            .push(Timeout::new(handshake, HANDSHAKE_TIMEOUT));
+                    }
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                    Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!("tls: failed to accept connection: {}", e);
+                        self.paused = Some(Delay::new(Instant::now() + ACCEPT_ERROR_DELAY));
+                    }
+                }
+            }
+            // failed handshakes make room to accept again
+            let mut failed = false;
+            loop {
+                match self.handshakes.poll() {
+                    Ok(Async::Ready(Some(stream))) => return Ok(Async::Ready(Some(stream))),
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        debug!("tls: handshake failed: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            if !failed {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// Server side of a TLS connection over `S`.
+pub(crate) struct TlsStream<S> {
+    io: S,
+    session: ServerSession,
+    /// `io` reached EOF
+    eof: bool,
+    /// close_notify is sent
+    closing: bool,
+}
+
+impl<S> TlsStream<S>
+where
+    S: Read + Write,
+{
+    fn new(io: S, config: &Arc<ServerConfig>) -> Self {
+        Self {
+            io,
+            session: ServerSession::new(config),
+            eof: false,
+            closing: false,
+        }
+    }
+
+    /// read and process TLS records, returns bytes read from `io`
+    fn read_io(&mut self) -> io::Result<usize> {
+        let n = self.session.read_tls(&mut self.io)?;
+        let res = self.session.process_new_packets();
+        // replies to the records, e.g. alerts, are sent as far as `io` accepts them
+        if let Err(e) = self.write_io() {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        res.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(n)
+    }
+
+    /// write pending TLS records
+    fn write_io(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.io)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// subject of the verified client certificate
+    pub(crate) fn client_cert(&self) -> Option<ClientCert> {
+        let certs = self.session.get_peer_certificates()?;
+        let cert = certs.first()?;
+        let (_rest, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+        Some(ClientCert {
+            subject: cert.subject().to_string(),
+        })
+    }
+}
+
+impl<S> Read for TlsStream<S>
+where
+    S: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.read(buf) {
+                Ok(0) if !buf.is_empty() && !self.eof => {}
+                Ok(n) => return Ok(n),
+                // close_notify of the client
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            if self.read_io()? == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<S> Write for TlsStream<S>
+where
+    S: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let n = self.session.write(buf)?;
+            match self.write_io() {
+                Ok(()) => {}
+                // records are buffered, sent on next write or flush
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && n > 0 => {}
+                Err(e) => return Err(e),
+            }
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        self.write_io()?;
+        self.io.flush()
+    }
+}
+
+impl<S> AsyncRead for TlsStream<S> where S: AsyncRead + AsyncWrite {}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.closing {
+            self.session.send_close_notify();
+            self.closing = true;
+        }
+        try_nb!(self.flush());
+        self.io.shutdown()
+    }
+}
+
+impl TlsStream<TcpStream> {
+    pub(crate) fn get_ref(&self) -> &TcpStream {
+        &self.io
+    }
+}
+
+/// Resolves to the stream once the handshake is complete.
+struct Handshake<S>(Option<TlsStream<S>>);
+
+impl<S> Future for Handshake<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let stream = self.0.as_mut().expect("polled after completion");
+            loop {
+                try_nb!(stream.write_io());
+                if !stream.session.is_handshaking() {
+                    break;
+                }
+                if try_nb!(stream.read_io()) == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        Ok(Async::Ready(
+            self.0.take().expect("polled after completion"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    use futures::{future, stream};
+    use rustls::{ClientConfig, ClientSession, StreamOwned};
+    use tokio::net::TcpListener;
+    use tokio::reactor::Handle;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn read_certs(name: &str) -> Vec<rustls::Certificate> {
+        pemfile::certs(&mut BufReader::new(File::open(testdata(name)).unwrap())).unwrap()
+    }
+
+    /// client which trusts the test CA and presents the client certificate
+    fn client_config() -> Arc<ClientConfig> {
+        let mut config = ClientConfig::new();
+        let ca = File::open(testdata("ca.pem")).unwrap();
+        config
+            .root_store
+            .add_pem_file(&mut BufReader::new(ca))
+            .unwrap();
+        let key = File::open(testdata("client.key")).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut BufReader::new(key)).unwrap();
+        config.set_single_client_cert(read_certs("client.pem"), key[0].clone());
+        Arc::new(config)
+    }
+
+    #[test]
+    fn client_cert_subject_is_read() {
+        let tls = TlsConfig::new(testdata("server.pem"), testdata("server.key"))
+            .and_then(|tls| tls.client_ca(testdata("ca.pem")))
+            .unwrap();
+        let config = tls.server_config(Vec::new());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // replies the client certificate subject of the first connection
+        std::thread::spawn(move || {
+            let mut rt = Runtime::new().unwrap();
+            let f = future::lazy(move || {
+                let listener = TcpListener::from_std(listener, &Handle::default()).unwrap();
+                TlsIncoming::new(listener.incoming(), config)
+                    .into_future()
+                    .map_err(|(e, _)| e)
+            })
+            .and_then(|(stream, _)| {
+                let stream = stream.expect("no connection");
+                let subject = stream.client_cert().expect("no client cert").subject;
+                tokio::io::write_all(stream, format!("{}\n", subject))
+            });
+            rt.block_on(f).unwrap();
+        });
+
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let session = ClientSession::new(&client_config(), dns_name);
+        let sock = std::net::TcpStream::connect(addr).unwrap();
+        let mut stream = io::BufReader::new(StreamOwned::new(session, sock));
+        let mut subject = String::new();
+        stream.read_line(&mut subject).unwrap();
+        assert_eq!(subject.trim_end(), "CN=client, O=serv");
+    }
+
+    #[test]
+    fn reload_replaces_certificate() {
+        let dir = std::env::temp_dir().join(format!("serv-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::copy(testdata("server.pem"), &cert).unwrap();
+        std::fs::copy(testdata("server.key"), &key).unwrap();
+        let tls = TlsConfig::new(&cert, &key).unwrap();
+        let served = || tls.certs.resolve(None, &[]).unwrap().cert;
+        assert_eq!(served(), read_certs("server.pem"));
+
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(served(), read_certs("server.pem"));
+
+        std::fs::copy(testdata("server2.pem"), &cert).unwrap();
+        std::fs::copy(testdata("server2.key"), &key).unwrap();
+        tls.reload().unwrap();
+        assert_eq!(served(), read_certs("server2.pem"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accept_errors_pause_accepting() {
+        let config = TlsConfig::new(testdata("server.pem"), testdata("server.key"))
+            .unwrap()
+            .server_config(Vec::new());
+        // too many open files
+        let incoming = stream::iter_result(vec![Err(io::Error::from_raw_os_error(24))]);
+        let mut incoming = TlsIncoming::new(incoming, config);
+        let mut rt = Runtime::new().unwrap();
+        let polled = rt.block_on(future::lazy(|| incoming.poll())).unwrap();
+        assert!(polled.is_not_ready());
+        assert!(incoming.paused.is_some());
+    }
+}
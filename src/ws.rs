@@ -0,0 +1,484 @@
+use std::io;
+use std::sync::Arc;
+
+use base64;
+use futures::stream::FuturesUnordered;
+use hyper::upgrade::Upgraded;
+use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
+use tungstenite::error::Error as WsError;
+use tungstenite::protocol::{Message, Role, WebSocket};
+
+use super::*;
+use async::{AsyncService, AsyncServiceFn};
+use stream;
+
+/// `serv_state` builds `HyperService` which upgrades requests to WebSocket, and handles each
+/// text frame `{"id": ..., "req": ...}` with `F`. The reply is written as a text frame in the
+/// reply envelope with the same `id`, e.g. `{"id": 1, "status": "ok", "result": ...}`. Frames
+/// are handled concurrently, replies are written as they complete. Once `MAX_IN_FLIGHT` frames
+/// are being handled, further frames are read as replies complete. Binary frames get an error
+/// reply with `null` id.
+/// The handler futures are not `Send`, so the connection needs a current-thread executor, see
+/// `stream`.
+pub fn serv_state<F, S, Req, Resp, E>(state: S, f: F) -> HyperService
+where
+    F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
+    S: 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: serde::Serialize + 'static,
+    E: HandlerError,
+{
+    let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(&state, req));
+    Box::new(WsService::new(f))
+}
+
+/// `serv` builds WebSocket `HyperService` with given function `F`. It runs on a current-thread
+/// executor, see `serv_state`.
+pub fn serv<F, Req, Resp, E>(f: F) -> HyperService
+where
+    F: Fn(Req) -> Box<Future<Item = Resp, Error = E>> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: serde::Serialize + 'static,
+    E: HandlerError,
+{
+    let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(req));
+    Box::new(WsService::new(f))
+}
+
+/// `serv_state_send` builds WebSocket `HyperServiceSync` with given function `F` and state
+/// `S`.
+pub fn serv_state_send<F, S, Req, Resp, E>(state: S, f: F) -> HyperServiceSync
+where
+    F: for<'a> Fn(&'a S, Req) -> Box<Future<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: serde::Serialize + Send + 'static,
+    E: HandlerError + Send,
+{
+    let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(&state, req));
+    Box::new(WsServiceSend::new(f))
+}
+
+/// `serv_send` builds WebSocket `HyperServiceSync` with given function `F`.
+pub fn serv_send<F, Req, Resp, E>(f: F) -> HyperServiceSync
+where
+    F: Fn(Req) -> Box<Future<Item = Resp, Error = E> + Send> + Send + Sync + 'static,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: serde::Serialize + Send + 'static,
+    E: HandlerError + Send,
+{
+    let f = AsyncServiceFn::new(move |_ctx: &RequestContext, req| f(req));
+    Box::new(WsServiceSend::new(f))
+}
+
+/// Frames of a connection which are handled at the same time, see `serv_state`.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// Inbound frame, `id` is copied to the reply
+#[derive(Deserialize)]
+struct WsRequest {
+    #[serde(default)]
+    id: Value,
+    #[serde(default = "empty_req")]
+    req: Value,
+}
+
+fn empty_req() -> Value {
+    Value::Object(Map::new())
+}
+
+/// Outbound frame
+#[derive(Serialize)]
+#[serde(bound = "T: serde::Serialize")]
+struct WsReply<T: serde::Serialize, E> {
+    id: Value,
+    #[serde(flatten)]
+    reply: reply::ServiceReply<T, E>,
+}
+
+/// decode text frame `text` into correlation id and req, path params of the upgrade request are
+/// merged as for HTTP requests
+fn decode<R>(ctx: &RequestContext, text: Result<String, Error>) -> (Value, Result<R, Error>)
+where
+    R: for<'de> serde::Deserialize<'de>,
+{
+    let text = match text {
+        Ok(text) => text,
+        Err(e) => return (Value::Null, Err(e)),
+    };
+    let frame: WsRequest = match serde_json::from_str(&text) {
+        Ok(frame) => frame,
+        Err(e) => return (Value::Null, Err(ErrorKind::DecodeJson(e).into())),
+    };
+    let req = match ctx.path_params() {
+        Some(params) => params.merge(frame.req),
+        None => serde_json::from_value(frame.req).map_err(|e| ErrorKind::DecodeJson(e).into()),
+    };
+    (frame.id, req)
+}
+
+/// encode reply frame of `res` for request `id`
+fn encode<T, E>(id: Value, res: Result<T, E>) -> String
+where
+    T: serde::Serialize,
+    E: Debug + std::error::Error,
+{
+    let reply = WsReply {
+        id: id.clone(),
+        reply: reply::ServiceReply::from(res),
+    };
+    serde_json::to_string(&reply).unwrap_or_else(|e| {
+        let reply = WsReply {
+            id,
+            reply: reply::ServiceReply::<(), _>::from(Error::from(ErrorKind::EncodeJson(e))),
+        };
+        serde_json::to_string(&reply).unwrap_or_default()
+    })
+}
+
+/// `Sec-WebSocket-Accept` for `key`, see RFC 6455
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::default();
+    sha1.input(key);
+    sha1.input(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    base64::encode(&sha1.result())
+}
+
+/// whether comma-separated header `name` contains `token`
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+/// `101 Switching Protocols` for upgrade request `req`
+fn upgrade_response(req: &Request<Body>) -> Result<hyper::Response<Body>, Error> {
+    let headers = req.headers();
+    let key = match headers.get(SEC_WEBSOCKET_KEY) {
+        Some(key)
+            if has_token(headers, CONNECTION, "upgrade")
+                && has_token(headers, UPGRADE, "websocket")
+                && headers.get(SEC_WEBSOCKET_VERSION).map(|v| v.as_bytes()) == Some(b"13") =>
+        {
+            key
+        }
+        _ => return Err("websocket upgrade expected".into()),
+    };
+    let resp = hyper::Response::builder()
+        .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key(key.as_bytes()))
+        .body(Body::empty())?;
+    Ok(resp)
+}
+
+/// WebSocket connection which handles text frames with `handle` and writes replies of the
+/// futures it returns. Other data frames are passed to `handle` as errors.
+struct Connection<H, F> {
+    ws: WebSocket<Upgraded>,
+    handle: H,
+    replies: FuturesUnordered<F>,
+}
+
+impl<H, F> Connection<H, F>
+where
+    H: FnMut(Result<String, Error>) -> F,
+    F: Future<Item = String, Error = ()>,
+{
+    fn new(upgraded: Upgraded, handle: H) -> Self {
+        Self {
+            ws: WebSocket::from_raw_socket(upgraded, Role::Server, None),
+            handle,
+            replies: FuturesUnordered::new(),
+        }
+    }
+}
+
+/// whether the connection is still usable after `res`
+fn is_open(res: Result<(), WsError>) -> bool {
+    match res {
+        Ok(()) => true,
+        Err(WsError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => true,
+        Err(e) => {
+            debug!("websocket closed: {}", e);
+            false
+        }
+    }
+}
+
+impl<H, F> Future for Connection<H, F>
+where
+    H: FnMut(Result<String, Error>) -> F,
+    F: Future<Item = String, Error = ()>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            while let Ok(Async::Ready(Some(reply))) = self.replies.poll() {
+                if !is_open(self.ws.write_message(Message::Text(reply))) {
+                    return Ok(Async::Ready(()));
+                }
+            }
+            // polled again as replies complete
+            if self.replies.len() >= MAX_IN_FLIGHT {
+                break;
+            }
+            let text = match self.ws.read_message() {
+                Ok(Message::Text(text)) => Ok(text),
+                Ok(Message::Binary(_)) => {
+                    Err(ErrorKind::DecodeBody("binary frames are not supported".to_owned()).into())
+                }
+                // ping and close are answered by `WebSocket`
+                Ok(_) => continue,
+                Err(e) => {
+                    if is_open(Err(e)) {
+                        break;
+                    }
+                    return Ok(Async::Ready(()));
+                }
+            };
+            let reply = (self.handle)(text);
+            self.replies.push(reply);
+        }
+        if !is_open(self.ws.write_pending()) {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// `WsService` implements `hyper::service::Service` for `AsyncService` over WebSocket
+struct WsService<T> {
+    inner: SyncObj<T>,
+}
+
+impl<T> WsService<T> {
+    fn new(t: T) -> Self {
+        Self {
+            inner: SyncObj::new(t),
+        }
+    }
+}
+
+impl<T, Req, Resp, E> hyper::service::Service for WsService<T>
+where
+    T: AsyncService<Req = Req, Resp = Resp, E = E> + 'static,
+    Req: for<'de> serde::Deserialize<'de> + 'static,
+    Resp: serde::Serialize + 'static,
+    E: HandlerError,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFuture;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let resp = match upgrade_response(&req) {
+            Ok(resp) => resp,
+            Err(e) => {
+                let e = E::from(e);
+                let status = e.status();
+                return Box::new(ok(resp_serv_err_as(e, status, codec)));
+            }
+        };
+
+        let obj = self.inner.clone();
+        let (ctx, body) = RequestContext::from_request(req);
+        let ctx = SyncObj::new(ctx);
+        let handle = move |text| -> Box<Future<Item = String, Error = ()>> {
+            let (id, req) = decode(&ctx, text);
+            match req {
+                Ok(req) => Box::new(T::call(&obj, &ctx, req).then(|res| Ok(encode(id, res)))),
+                Err(e) => Box::new(ok(encode::<Resp, E>(id, Err(E::from(e))))),
+            }
+        };
+        let conn = body
+            .on_upgrade()
+            .map_err(|e| debug!("websocket upgrade failed: {}", e))
+            .and_then(|upgraded| Connection::new(upgraded, handle));
+        // spawned once polled, on the executor which runs the service
+        let f = lazy(move || match stream::spawn_local(conn) {
+            Ok(()) => Ok(resp),
+            Err(e) => {
+                let e = E::from(e);
+                let status = e.status();
+                Ok(resp_serv_err_as(e, status, codec))
+            }
+        });
+        Box::new(f)
+    }
+}
+
+/// `WsServiceSend` implements `hyper::service::Service` for `Send` and `Sync` `AsyncService`
+/// over WebSocket
+struct WsServiceSend<T> {
+    inner: Arc<T>,
+}
+
+impl<T> WsServiceSend<T> {
+    fn new(t: T) -> Self {
+        Self { inner: Arc::new(t) }
+    }
+}
+
+impl<T> Clone for WsServiceSend<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, Req, Resp, E> hyper::service::Service for WsServiceSend<T>
+where
+    T: AsyncService<Req = Req, Resp = Resp, E = E> + Send + Sync + 'static,
+    T::Future: Send,
+    Req: for<'de> serde::Deserialize<'de> + Send + 'static,
+    Resp: serde::Serialize + Send + 'static,
+    E: HandlerError + Send,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = HyperFutureSend;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let codec = Codec::reply(req.headers());
+        let resp = match upgrade_response(&req) {
+            Ok(resp) => resp,
+            Err(e) => {
+                let e = E::from(e);
+                let status = e.status();
+                return Box::new(ok(resp_serv_err_as(e, status, codec)));
+            }
+        };
+
+        let obj = self.inner.clone();
+        let (ctx, body) = RequestContext::from_request(req);
+        let ctx = Arc::new(ctx);
+        let handle = move |text| -> Box<Future<Item = String, Error = ()> + Send> {
+            let (id, req) = decode(&ctx, text);
+            match req {
+                Ok(req) => Box::new(T::call(&obj, &ctx, req).then(|res| Ok(encode(id, res)))),
+                Err(e) => Box::new(ok(encode::<Resp, E>(id, Err(E::from(e))))),
+            }
+        };
+        let conn = body
+            .on_upgrade()
+            .map_err(|e| debug!("websocket upgrade failed: {}", e))
+            .and_then(|upgraded| Connection::new(upgraded, handle));
+        // spawned once polled, on the executor which runs the service
+        let f = lazy(move || match stream::spawn(conn) {
+            Ok(()) => Ok(resp),
+            Err(e) => {
+                let e = E::from(e);
+                let status = e.status();
+                Ok(resp_serv_err_as(e, status, codec))
+            }
+        });
+        Box::new(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+
+    use server::{Routes, Server};
+    use test::{TestClient, TestResponse};
+
+    /// echo server, `"wait"` is never replied
+    fn server() -> Server {
+        let echo = serv(|req: String| -> Box<Future<Item = String, Error = Error>> {
+            if req == "wait" {
+                return Box::new(empty());
+            }
+            Box::new(ok(req))
+        });
+        let mut routes = Routes::new();
+        routes.push(Method::GET, "/ws", echo);
+        Server::new(routes)
+    }
+
+    fn upgrade_request() -> Request<Body> {
+        Request::get("/ws")
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn upgrade_is_accepted() {
+        let mut client = TestClient::new(server());
+        let resp: TestResponse<()> = client.request(upgrade_request());
+        resp.assert_status(StatusCode::SWITCHING_PROTOCOLS);
+        let accept = &resp.headers()[SEC_WEBSOCKET_ACCEPT];
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    /// WebSocket client of `server` running on another thread
+    fn connect() -> WebSocket<std::net::TcpStream> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+            rt.block_on(server().run_listener(listener))
+        });
+
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let url = format!("ws://{}/ws", addr);
+        tungstenite::client(url.as_str(), stream).unwrap().0
+    }
+
+    fn frame(id: usize, req: &str) -> Message {
+        Message::Text(format!(r#"{{"id": {}, "req": "{}"}}"#, id, req))
+    }
+
+    #[test]
+    fn frames_are_replied_with_their_id() {
+        let mut ws = connect();
+        let mut reply = |msg| -> Value {
+            ws.write_message(msg).unwrap();
+            match ws.read_message().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                msg => panic!("unexpected frame: {:?}", msg),
+            }
+        };
+
+        let resp = reply(frame(7, "hello"));
+        assert_eq!(resp["id"], 7);
+        assert_eq!(resp["status"], "ok");
+        assert_eq!(resp["result"], "hello");
+
+        let resp = reply(Message::Binary(b"{}".to_vec()));
+        assert_eq!(resp["id"], Value::Null);
+        assert_eq!(resp["status"], "error");
+    }
+
+    #[test]
+    fn frames_over_the_limit_wait_for_replies() {
+        let mut ws = connect();
+        for id in 0..MAX_IN_FLIGHT {
+            ws.write_message(frame(id, "wait")).unwrap();
+        }
+        ws.write_message(frame(MAX_IN_FLIGHT, "hello")).unwrap();
+        let timeout = Some(std::time::Duration::from_millis(200));
+        ws.get_mut().set_read_timeout(timeout).unwrap();
+        match ws.read_message() {
+            Err(WsError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            res => panic!("unexpected reply: {:?}", res),
+        }
+    }
+}